@@ -0,0 +1,188 @@
+use crate::Color;
+use cgmath::{Point3, Vector3};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+
+/// Arbitrary output variables the renderer can write next to the beauty image.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Aov {
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    DirectDiffuse,
+    /// Light arriving after more than one bounce. Only direct lighting is traced so far, the pass stays black.
+    Indirect,
+    /// Mirror and glossy reflection. All materials are diffuse so far, the pass stays black.
+    Specular,
+    Emission,
+    Shadow,
+    ObjectId,
+    MaterialId,
+}
+
+impl Aov {
+    pub fn all() -> Vec<Aov> {
+        vec![Aov::Albedo, Aov::Normal, Aov::Depth, Aov::Position, Aov::DirectDiffuse, Aov::Indirect,
+             Aov::Specular, Aov::Emission, Aov::Shadow, Aov::ObjectId, Aov::MaterialId]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::DirectDiffuse => "direct_diffuse",
+            Aov::Indirect => "indirect",
+            Aov::Specular => "specular",
+            Aov::Emission => "emission",
+            Aov::Shadow => "shadow",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+        }
+    }
+
+    pub fn filter(&self) -> AovFilter {
+        match *self {
+            Aov::Normal | Aov::Depth | Aov::Position => AovFilter::Covered,
+            Aov::ObjectId | Aov::MaterialId => AovFilter::First,
+            _ => AovFilter::Pixel,
+        }
+    }
+}
+
+/// How the samples of a pixel are combined into the value of a pass.
+#[derive(Clone, Copy, PartialEq)]
+pub enum AovFilter {
+    /// Averaged over all samples, misses count as black like in the beauty image.
+    Pixel,
+    /// Averaged over the samples that hit something, misses would drag the value towards zero.
+    Covered,
+    /// The first sample that hit something, blending two IDs yields a third, unrelated one.
+    First,
+}
+
+/// Every pass value of a single camera ray.
+pub struct AovSample {
+    pub beauty: Color,
+    pub albedo: Color,
+    pub normal: Vector3<f64>,
+    pub depth: f64,
+    pub position: Point3<f64>,
+    pub direct_diffuse: Color,
+    pub emission: Color,
+    pub shadow: Color,
+    pub object_id: u32,
+    pub material_id: u32,
+}
+
+impl AovSample {
+    pub fn value(&self, aov: Aov) -> [f32; 3] {
+        match aov {
+            Aov::Albedo => color_value(&self.albedo),
+            Aov::Normal => [self.normal.x as f32, self.normal.y as f32, self.normal.z as f32],
+            Aov::Depth => [self.depth as f32; 3],
+            Aov::Position => [self.position.x as f32, self.position.y as f32, self.position.z as f32],
+            Aov::DirectDiffuse => color_value(&self.direct_diffuse),
+            Aov::Indirect | Aov::Specular => [0.0; 3],
+            Aov::Emission => color_value(&self.emission),
+            Aov::Shadow => color_value(&self.shadow),
+            Aov::ObjectId => [self.object_id as f32; 3],
+            Aov::MaterialId => [self.material_id as f32; 3],
+        }
+    }
+}
+
+fn color_value(color: &Color) -> [f32; 3] {
+    [color.red, color.green, color.blue]
+}
+
+/// Stable ID for a name (32 bit FNV-1a), kept below 2^24 so it survives a round trip through an `f32` channel.
+pub fn id_from_name(name: &str) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in name.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash & 0x00ff_ffff
+}
+
+/// Floating point image of one pass, rows stored top to bottom.
+pub struct AovBuffer {
+    pub aov: Aov,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<[f32; 3]>,
+}
+
+impl AovBuffer {
+    pub fn new(aov: Aov, width: u32, height: u32) -> AovBuffer {
+        AovBuffer {
+            aov: aov,
+            width: width,
+            height: height,
+            data: vec![[0.0; 3]; (width * height) as usize],
+        }
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, value: [f32; 3]) {
+        self.data[(y * self.width + x) as usize] = value;
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> [f32; 3] {
+        self.data[(y * self.width + x) as usize]
+    }
+
+    /// Writes the buffer as a little endian PFM (portable float map), which keeps the full float range.
+    pub fn write_pfm(&self, path: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).truncate(true).create(true).open(path)?;
+        let mut bytes = format!("PF\n{} {}\n-1.0\n", self.width, self.height).into_bytes();
+        // PFM scanlines run bottom to top
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                for channel in self.get(x, y).iter() {
+                    bytes.extend_from_slice(&channel.to_le_bytes());
+                }
+            }
+        }
+        file.write_all(&bytes)
+    }
+}
+
+/// Derives the file of a pass from the beauty output, e.g. `output.png` -> `output.depth.pfm`.
+pub fn aov_path(output_path: &str, aov: Aov) -> String {
+    let stem = match output_path.rfind('.') {
+        Some(i) if !output_path[i..].contains('/') => &output_path[..i],
+        _ => output_path,
+    };
+    format!("{}.{}.pfm", stem, aov.name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_from_name_is_fnv1a_truncated_to_24_bits() {
+        assert_eq!(id_from_name(""), 0x1c_9dc5);
+        assert_eq!(id_from_name("a"), 0x0c_292c);
+        assert_eq!(id_from_name("foobar"), 0x9c_f968);
+        assert_ne!(id_from_name("Sphere Red"), id_from_name("Sphere Blue"));
+    }
+
+    #[test]
+    fn id_from_name_survives_f32() {
+        for name in ["Sphere Red", "Plane Back", "root/Sphere root", "\u{fffd}"].iter() {
+            let id = id_from_name(name);
+            assert_eq!(id as f32 as u32, id);
+        }
+    }
+
+    #[test]
+    fn aov_path_replaces_extension() {
+        assert_eq!(aov_path("output.png", Aov::Depth), "output.depth.pfm");
+        assert_eq!(aov_path("renders/frame", Aov::ObjectId), "renders/frame.object_id.pfm");
+        assert_eq!(aov_path("out.d/frame", Aov::Albedo), "out.d/frame.albedo.pfm");
+    }
+}
@@ -0,0 +1,113 @@
+use crate::Ray;
+use cgmath::{Point3, Vector3, Matrix4, Transform};
+
+/// Axis aligned bounding box, an empty box has `min > max`.
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Point3<f64>,
+    pub max: Point3<f64>,
+}
+
+impl Aabb {
+    pub fn new(min: Point3<f64>, max: Point3<f64>) -> Aabb {
+        Aabb {
+            min: min,
+            max: max,
+        }
+    }
+
+    pub fn empty() -> Aabb {
+        Aabb::new(Point3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+                  Point3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY))
+    }
+
+    /// Box spanning `-half_extents` to `half_extents` around the origin.
+    pub fn centered(half_extents: Vector3<f64>) -> Aabb {
+        Aabb::new(Point3::new(-half_extents.x, -half_extents.y, -half_extents.z),
+                  Point3::new(half_extents.x, half_extents.y, half_extents.z))
+    }
+
+    pub fn from_points(points: &[Point3<f64>]) -> Aabb {
+        let mut bounds = Aabb::empty();
+        for point in points {
+            bounds = bounds.grow(*point);
+        }
+        bounds
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&self, point: Point3<f64>) -> Aabb {
+        Aabb::new(Point3::new(self.min.x.min(point.x), self.min.y.min(point.y), self.min.z.min(point.z)),
+                  Point3::new(self.max.x.max(point.x), self.max.y.max(point.y), self.max.z.max(point.z)))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        if other.is_empty() {
+            return *self;
+        }
+        self.grow(other.min).grow(other.max)
+    }
+
+    /// Box grown on every side by `relative` times its largest coordinate, so rounding in ray tests can not
+    /// miss surfaces lying on its faces.
+    pub fn padded(&self, relative: f64) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        let scale = [self.min.x, self.min.y, self.min.z, self.max.x, self.max.y, self.max.z].iter()
+            .fold(0.0f64, |m, x| m.max(x.abs()));
+        let pad = Vector3::new(1.0, 1.0, 1.0) * (scale * relative).max(f64::MIN_POSITIVE);
+        Aabb::new(self.min - pad, self.max + pad)
+    }
+
+    pub fn center(&self) -> Point3<f64> {
+        self.min + (self.max - self.min) * 0.5
+    }
+
+    pub fn corners(&self) -> [Point3<f64>; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Point3::new(a.x, a.y, a.z), Point3::new(b.x, a.y, a.z), Point3::new(a.x, b.y, a.z), Point3::new(b.x, b.y, a.z),
+            Point3::new(a.x, a.y, b.z), Point3::new(b.x, a.y, b.z), Point3::new(a.x, b.y, b.z), Point3::new(b.x, b.y, b.z),
+        ]
+    }
+
+    /// Box enclosing this box after `transform`, may be larger than the tightest box of the transformed shape.
+    pub fn transform(&self, transform: &Matrix4<f64>) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        let mut bounds = Aabb::empty();
+        for corner in self.corners().iter() {
+            bounds = bounds.grow(transform.transform_point(*corner));
+        }
+        bounds
+    }
+
+    /// Slab test, returns the distances at which the ray enters and leaves the box clipped to the ray extent.
+    pub fn intersect(&self, ray: &Ray) -> Option<(f64, f64)> {
+        let (mut t0, mut t1) = (ray.t_min, ray.t_max);
+        for axis in 0..3 {
+            let inv = 1.0 / ray.direction[axis];
+            let mut near = (self.min[axis] - ray.origin[axis]) * inv;
+            let mut far = (self.max[axis] - ray.origin[axis]) * inv;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // NaN from 0 * inf leaves the bounds unchanged
+            if near > t0 {
+                t0 = near;
+            }
+            if far < t1 {
+                t1 = far;
+            }
+            if t0 > t1 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{Node, Color, Ray, Material, HitRecord, Aabb, Shape};
+use crate::compiled_scene::SceneCompiler;
+use crate::node::NodeCore;
+use crate::hit_record::abs;
+use std::sync::Arc;
+use cgmath::{InnerSpace, Vector2, Vector3, Matrix4, EuclideanSpace};
+
+/// Box centered on the local origin, rotate it through its transform to get an oriented box.
+#[derive(Clone)]
+pub struct Cuboid {
+    /// Half of the edge lengths along the local axes.
+    pub half_extents: Vector3<f64>,
+}
+
+/// Scene node placing a `Cuboid`.
+pub struct BoxNode {
+    pub core: NodeCore,
+    pub shape: Arc<Cuboid>,
+    pub material: Arc<Material>,
+}
+
+impl BoxNode {
+    /// `extents` are the full edge lengths along the local x, y and z axes.
+    pub fn new(name: String, transform: Matrix4<f64>, extents: Vector3<f64>, color: Color) -> BoxNode {
+        BoxNode {
+            core: NodeCore::new(name, transform),
+            shape: Arc::new(Cuboid {
+                half_extents: extents * 0.5,
+            }),
+            material: Arc::new(Material::from_color(color)),
+        }
+    }
+}
+
+impl Cuboid {
+    /// Hit on the face perpendicular to `axis`, every face maps the unit square upright when seen from outside.
+    fn face_hit(&self, ray: &Ray, distance: f64, axis: usize) -> HitRecord {
+        let mut position = ray.origin + ray.direction * distance;
+        let sign = if position[axis] < 0.0 { -1.0 } else { 1.0 };
+        position[axis] = sign * self.half_extents[axis];
+
+        let mut normal = Vector3::new(0.0, 0.0, 0.0);
+        normal[axis] = sign;
+        // Side faces have v pointing up, the top and bottom faces have v pointing away from +z
+        let up = if axis == 1 { Vector3::new(0.0, 0.0, -sign) } else { Vector3::new(0.0, 1.0, 0.0) };
+        let right = up.cross(normal);
+        let width = 2.0 * abs(right).dot(self.half_extents);
+        let height = 2.0 * abs(up).dot(self.half_extents);
+        let local = position.to_vec();
+        let uv = Vector2::new(local.dot(right) / width + 0.5, local.dot(up) / height + 0.5);
+
+        let mut hit = HitRecord::new(ray, distance, normal, uv, right * width, up * height, None);
+        hit.position = position;
+        hit
+    }
+}
+
+impl Shape for Cuboid {
+    fn intersect_all(&self, ray: &Ray) -> Vec<HitRecord> {
+        let (mut t0, mut t1) = (f64::NEG_INFINITY, f64::INFINITY);
+        let (mut axis0, mut axis1) = (0, 0);
+        for axis in 0..3 {
+            let inv = 1.0 / ray.direction[axis];
+            let mut near = (-self.half_extents[axis] - ray.origin[axis]) * inv;
+            let mut far = (self.half_extents[axis] - ray.origin[axis]) * inv;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            if near > t0 {
+                t0 = near;
+                axis0 = axis;
+            }
+            if far < t1 {
+                t1 = far;
+                axis1 = axis;
+            }
+        }
+        if t0 > t1 {
+            return vec![];
+        }
+        [(t0, axis0), (t1, axis1)].iter()
+            .filter(|(t, _)| ray.contains(*t))
+            .map(|(t, axis)| self.face_hit(ray, *t, *axis))
+            .collect()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::centered(self.half_extents))
+    }
+}
+
+impl Node for BoxNode {
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
+
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
+    }
+
+    fn get_shape(&self, _compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        return Some(self.shape.clone());
+    }
+
+    fn get_material(&self) -> Option<Arc<Material>> {
+        return Some(Arc::clone(&self.material));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Point3, InnerSpace};
+
+    #[test]
+    fn axis_aligned_ray_enters_and_leaves() {
+        let cuboid = Cuboid { half_extents: Vector3::new(1.0, 2.0, 3.0) };
+        let hits = cuboid.intersect_all(&Ray::new(Point3::new(0.2, 0.3, 10.0), Vector3::new(0.0, 0.0, -1.0)));
+        assert_eq!(hits.len(), 2);
+        assert!((hits[0].distance - 7.0).abs() < 1e-9 && (hits[1].distance - 13.0).abs() < 1e-9);
+        assert!(hits[0].front_face && !hits[1].front_face);
+        assert!((hits[0].geometric_normal - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+    }
+}
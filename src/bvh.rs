@@ -0,0 +1,135 @@
+use crate::{Ray, Aabb};
+use cgmath::Point3;
+
+/// Items per leaf below which a node is not split any further.
+const MAX_LEAF_SIZE: usize = 4;
+
+/// Relative padding of the item bounds, flat items like rectangles lie exactly on their bounds.
+const BOUNDS_PADDING: f64 = 1e-9;
+
+struct BvhNode {
+    bounds: Aabb,
+    /// Leaves cover `items[start..start + count]`, interior nodes have `count == 0`.
+    start: usize,
+    count: usize,
+    /// Interior nodes keep their first child right after themselves and the second one here.
+    second: usize,
+    axis: usize,
+}
+
+/// Bounding volume hierarchy over a list of items, stored as a flat array in depth-first order.
+///
+/// Items without bounds are kept aside and tested for every ray.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    items: Vec<usize>,
+    unbounded: Vec<usize>,
+}
+
+impl Bvh {
+    /// Builds the hierarchy over items with the given bounds, `None` marks an unbounded item.
+    pub fn new(bounds: &[Option<Aabb>]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: vec![],
+            items: vec![],
+            unbounded: vec![],
+        };
+        let mut bounded = vec![];
+        for (index, item) in bounds.iter().enumerate() {
+            match item {
+                Some(item) if !item.is_empty() => bounded.push((index, item.padded(BOUNDS_PADDING), item.center())),
+                Some(_) => {}
+                None => bvh.unbounded.push(index),
+            }
+        }
+        if !bounded.is_empty() {
+            bvh.build(&mut bounded);
+        }
+        bvh
+    }
+
+    fn build(&mut self, items: &mut [(usize, Aabb, Point3<f64>)]) -> usize {
+        let index = self.nodes.len();
+        let mut bounds = Aabb::empty();
+        let mut centers = Aabb::empty();
+        for item in items.iter() {
+            bounds = bounds.union(&item.1);
+            centers = centers.grow(item.2);
+        }
+        let extent = centers.max - centers.min;
+        let axis = if extent.x > extent.y && extent.x > extent.z { 0 } else if extent.y > extent.z { 1 } else { 2 };
+
+        if items.len() <= MAX_LEAF_SIZE || extent[axis] <= 0.0 {
+            self.nodes.push(BvhNode { bounds: bounds, start: self.items.len(), count: items.len(), second: 0, axis: axis });
+            self.items.extend(items.iter().map(|item| item.0));
+            return index;
+        }
+
+        // Split at the middle of the centers, fall back to equal halves if everything lands on one side
+        let middle = centers.center()[axis];
+        let mut split = 0;
+        for i in 0..items.len() {
+            if items[i].2[axis] < middle {
+                items.swap(i, split);
+                split += 1;
+            }
+        }
+        if split == 0 || split == items.len() {
+            split = items.len() / 2;
+            items.select_nth_unstable_by(split, |a, b| a.2[axis].total_cmp(&b.2[axis]));
+        }
+
+        self.nodes.push(BvhNode { bounds: bounds, start: 0, count: 0, second: 0, axis: axis });
+        let (first, second) = items.split_at_mut(split);
+        self.build(first);
+        let second = self.build(second);
+        self.nodes[index].second = second;
+        index
+    }
+
+    /// Bounds of all items, `None` if any of them is unbounded.
+    pub fn bounds(&self) -> Option<Aabb> {
+        if !self.unbounded.is_empty() {
+            return None;
+        }
+        Some(self.nodes.first().map_or(Aabb::empty(), |node| node.bounds))
+    }
+
+    /// Calls `visit` with every item whose bounds the ray may hit, nearer subtrees first.
+    ///
+    /// `visit` returns the distance of the nearest hit found so far, subtrees beyond it are skipped.
+    pub fn traverse<F: FnMut(usize) -> f64>(&self, ray: &Ray, mut visit: F) {
+        let mut ray = Ray {
+            origin: ray.origin,
+            direction: ray.direction,
+            t_min: ray.t_min,
+            t_max: ray.t_max,
+            time: ray.time,
+        };
+        for index in &self.unbounded {
+            ray.t_max = ray.t_max.min(visit(*index));
+        }
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut stack = vec![0];
+        while let Some(current) = stack.pop() {
+            let node = &self.nodes[current];
+            if node.bounds.intersect(&ray).is_none() {
+                continue;
+            }
+            if node.count > 0 {
+                for item in &self.items[node.start..node.start + node.count] {
+                    ray.t_max = ray.t_max.min(visit(*item));
+                }
+            } else if ray.direction[node.axis] < 0.0 {
+                stack.push(current + 1);
+                stack.push(node.second);
+            } else {
+                stack.push(node.second);
+                stack.push(current + 1);
+            }
+        }
+    }
+}
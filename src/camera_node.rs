@@ -1,83 +1,588 @@
-use crate::{Node3D, Node, Ray, Color};
-use std::rc::{Weak, Rc};
-use cgmath::{Vector3, Matrix4};
-use std::cell::RefCell;
+use crate::{Node, Ray, Rng, MotionChain};
+use crate::node::NodeCore;
+use cgmath::{Vector2, Vector3, Matrix4, Point3, InnerSpace, SquareMatrix, Transform, EuclideanSpace};
+use std::f64::consts::PI;
 
+/// Lens and sensor sizes are given in millimeters, scene units are taken to be meters.
+const MILLIMETERS_PER_UNIT: f64 = 1000.0;
+
+/// Camera placed in the scene graph like any other node, scenes find their cameras by name.
 pub struct CameraNode {
-    childs: Vec<Rc<RefCell<Node>>>,
-    parent: Weak<Rc<RefCell<Node>>>,
-    size: usize,
-    pub name: String,
+    pub core: NodeCore,
     //center: Point3<f64>,
-    pub frame_transform: Matrix4<f64>,
-    pub world_transform: Matrix4<f64>,
+    /// Direction the camera looks in, in its local space with +y as up.
     pub viewing_direction: Vector3<f64>,
     pub plane_point: Vector3<f64>,          
     pub image_width: usize,
     pub image_height: usize, 
-    plane_with: f64,
-    plane_height: f64,
+    /// Vertical field of view in degrees of the perspective projection, replaced by the one of the lens if there is one.
+    pub fov: f64,
+    pub projection: Projection,
+    /// Thin lens for depth of field with the perspective projection, the camera is a pinhole if `None`.
+    pub lens: Option<ThinLens>,
+    /// Renders a left and a right eye view into one image if set.
+    pub stereo: Option<Stereo>,
+    /// Times the shutter opens and closes, moving nodes blur over this interval when it is not empty.
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+}
+
+/// How the two eye views of a stereo render share the output image.
+#[derive(Clone, Copy)]
+pub enum StereoLayout {
+    /// Left eye in the left half, the image is twice as wide as the camera's.
+    SideBySide,
+    /// Left eye in the top half, the image is twice as high as the camera's.
+    OverUnder,
+}
+
+/// Stereo pair around the camera, the eyes sit on its local x axis.
+///
+/// With the equirectangular projection this renders omni-directional stereo: the eyes turn with the longitude of
+/// every pixel, and their separation shrinks towards the poles to keep the panorama comfortable to look around.
+#[derive(Clone)]
+pub struct Stereo {
+    /// Distance between the eyes in scene units.
+    pub interocular_distance: f64,
+    /// Distance at which the views of both eyes meet, the eyes look parallel if infinite.
+    pub convergence_distance: f64,
+    pub layout: StereoLayout,
+}
+
+impl Stereo {
+    /// Parallel eyes 65 mm apart, assuming scene units are meters.
+    pub fn new(layout: StereoLayout) -> Stereo {
+        Stereo {
+            interocular_distance: 0.065,
+            convergence_distance: f64::INFINITY,
+            layout: layout,
+        }
+    }
+}
+
+/// How the camera maps directions onto the image.
+#[derive(Clone)]
+pub enum Projection {
+    Perspective,
+    /// Parallel rays, the view is `width` scene units wide.
+    Orthographic { width: f64 },
+    /// Angle from the viewing direction proportional to the distance from the image center. The image circle
+    /// spans `fov` degrees, up to 360, across the shorter image side, pixels outside it stay empty.
+    EquidistantFisheye { fov: f64 },
+    /// Like `EquidistantFisheye` but with equal image area per solid angle.
+    EquisolidFisheye { fov: f64 },
+    /// Full 360x180 degree panorama, longitude along x and latitude along y, centered on the viewing direction.
+    Equirectangular,
+}
+
+/// Where a `ThinLens` is focused.
+#[derive(Clone)]
+pub enum Focus {
+    /// Distance from the camera along its viewing direction.
+    Distance(f64),
+    /// The origin of the first node with this name.
+    Node(String),
+}
+
+/// Physical lens in front of a sensor, points off the focus plane blur into the shape of the aperture.
+#[derive(Clone)]
+pub struct ThinLens {
+    /// Focal length in millimeters.
+    pub focal_length: f64,
+    /// Aperture as an f-number, the lens opening is `focal_length / f_stop` wide.
+    pub f_stop: f64,
+    /// Sensor size in millimeters, fitted to the image so it covers it in both directions.
+    pub sensor_width: f64,
+    pub sensor_height: f64,
+    pub focus: Focus,
+    /// Number of aperture blades, polygonal bokeh for three or more, a round aperture otherwise.
+    pub blades: u32,
+    /// Rotation of the aperture polygon in degrees.
+    pub blade_rotation: f64,
+}
+
+impl ThinLens {
+    /// Round aperture in front of a full frame 36x24 mm sensor.
+    pub fn new(focal_length: f64, f_stop: f64, focus: Focus) -> ThinLens {
+        ThinLens {
+            focal_length: focal_length,
+            f_stop: f_stop,
+            sensor_width: 36.0,
+            sensor_height: 24.0,
+            focus: focus,
+            blades: 0,
+            blade_rotation: 0.0,
+        }
+    }
+
+    /// Vertical field of view in degrees for an image with the given aspect ratio.
+    pub fn fov(&self, aspect_ratio: f64) -> f64 {
+        let sensor_aspect = self.sensor_width / self.sensor_height;
+        let height = if aspect_ratio > sensor_aspect { self.sensor_width / aspect_ratio } else { self.sensor_height };
+        (2.0 * (height * 0.5 / self.focal_length).atan()).to_degrees()
+    }
+
+    /// Radius of the lens opening in scene units.
+    pub fn aperture_radius(&self) -> f64 {
+        self.focal_length / self.f_stop * 0.5 / MILLIMETERS_PER_UNIT
+    }
 }
 
 impl CameraNode {
     pub fn new(name: String, transform: Matrix4<f64>, direction: Vector3<f64>, width: usize, height: usize) -> CameraNode {
         CameraNode {
-            childs: vec![],
-            parent: Weak::new(),
-            size: 0,
-            name: name,
-            frame_transform: transform,
-            world_transform: transform,
+            core: NodeCore::new(name, transform),
             viewing_direction: direction,
             plane_point: Vector3::new(0.0, 0.0, 0.0),
             image_width: width,
             image_height: height,
-            plane_with: 0.0,
-            plane_height: 0.0,
+            fov: 45.0,
+            projection: Projection::Perspective,
+            lens: None,
+            stereo: None,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+        }
+    }
+
+    /// Camera space, looking down -z with +y up, to the local space of the node.
+    fn view_orientation(&self) -> Matrix4<f64> {
+        let up = if self.viewing_direction.normalize().y.abs() > 0.999 {
+            Vector3::new(0.0, 0.0, -1.0)
+        } else {
+            Vector3::new(0.0, 1.0, 0.0)
+        };
+        let view = Matrix4::look_at_dir(Point3::origin(), self.viewing_direction, up);
+        view.invert().unwrap_or(Matrix4::identity())
+    }
+}
+
+/// Camera with its placement baked into world space, the form the renderer reads.
+pub struct CompiledCamera {
+    pub name: String,
+    /// Camera space, looking down -z with +y up, to world space.
+    pub camera_to_world: Matrix4<f64>,
+    pub image_width: usize,
+    pub image_height: usize,
+    pub fov: f64,
+    pub projection: Projection,
+    pub stereo: Option<Stereo>,
+    /// Lens radius in scene units, zero for a pinhole.
+    pub aperture_radius: f64,
+    /// Distance of the plane in focus along the viewing direction.
+    pub focus_distance: f64,
+    /// Node to focus on, resolved into `focus_distance` by scene compilation.
+    pub focus_node: Option<String>,
+    pub blades: u32,
+    pub blade_rotation: f64,
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+    /// `camera_to_world` over time if the camera node or one of its ancestors moves, set by scene compilation.
+    pub motion: Option<MotionChain>,
+}
+
+impl CompiledCamera {
+    /// Size of the rendered image, both eye views for stereo cameras.
+    pub fn output_size(&self) -> (u32, u32) {
+        let (width, height) = (self.image_width as u32, self.image_height as u32);
+        match self.stereo {
+            None => (width, height),
+            Some(Stereo { layout: StereoLayout::SideBySide, .. }) => (width * 2, height),
+            Some(Stereo { layout: StereoLayout::OverUnder, .. }) => (width, height * 2),
+        }
+    }
+
+    /// World space primary ray through the raster position `(x, y)` of the output image, pixel centers lie at `+0.5`.
+    /// `None` for positions the projection does not cover, like the corners of a fisheye image.
+    ///
+    /// With a lens the ray starts on a point of the aperture sampled with `rng` and passes through the point of the
+    /// focus plane the pinhole ray would hit. With an open shutter the ray is sent at a random time in between.
+    pub fn generate_ray(&self, x: f64, y: f64, rng: &mut Rng) -> Option<Ray> {
+        let time = if self.shutter_close > self.shutter_open {
+            self.shutter_open + (self.shutter_close - self.shutter_open) * rng.next_f64()
+        } else {
+            self.shutter_open
+        };
+        let (width, height) = (self.image_width as f64, self.image_height as f64);
+        // Position within the view of one eye, -1 for the left one, 1 for the right one and 0 without stereo
+        let (x, y, eye) = match self.stereo {
+            None => (x, y, 0.0),
+            Some(Stereo { layout: StereoLayout::SideBySide, .. }) =>
+                if x < width { (x, y, -1.0) } else { (x - width, y, 1.0) },
+            Some(Stereo { layout: StereoLayout::OverUnder, .. }) =>
+                if y < height { (x, y, -1.0) } else { (x, y - height, 1.0) },
+        };
+        // Image plane coordinates with y up, -1 to 1 across the shorter side
+        let scale = 2.0 / width.min(height);
+        let (u, v) = ((x - width * 0.5) * scale, (height * 0.5 - y) * scale);
+
+        let ray = match self.projection {
+            Projection::Perspective => {
+                let ray = Ray::create_prime(x, y, width as u32, height as u32, self.fov);
+                if self.aperture_radius <= 0.0 {
+                    ray
+                } else {
+                    let focus = Point3::from_vec(ray.direction * (self.focus_distance / -ray.direction.z));
+                    let lens = sample_aperture(rng.next_2d(), self.blades, self.blade_rotation) * self.aperture_radius;
+                    let origin = Point3::new(lens.x, lens.y, 0.0);
+                    Ray::new(origin, (focus - origin).normalize())
+                }
+            }
+            Projection::Orthographic { width: view_width } => {
+                let units = view_width / width;
+                Ray::new(Point3::new((x - width * 0.5) * units, (height * 0.5 - y) * units, 0.0),
+                         Vector3::new(0.0, 0.0, -1.0))
+            }
+            Projection::EquidistantFisheye { fov } => {
+                let r = (u * u + v * v).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+                fisheye_ray(u, v, r, r * (fov * 0.5).to_radians())
+            }
+            Projection::EquisolidFisheye { fov } => {
+                let r = (u * u + v * v).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+                let theta = 2.0 * (r * (fov * 0.25).to_radians().sin()).clamp(-1.0, 1.0).asin();
+                fisheye_ray(u, v, r, theta)
+            }
+            Projection::Equirectangular => {
+                let longitude = (x / width - 0.5) * 2.0 * PI;
+                let latitude = (0.5 - y / height) * PI;
+                Ray::new(Point3::origin(), Vector3::new(latitude.cos() * longitude.sin(), latitude.sin(),
+                                                        -latitude.cos() * longitude.cos()))
+            }
+        };
+        let ray = match self.stereo {
+            Some(ref stereo) => self.eye_ray(ray, stereo, eye),
+            None => ray,
+        };
+        let camera_to_world = match self.motion {
+            Some(ref motion) => motion.at(time),
+            None => self.camera_to_world,
+        };
+        let mut ray = Ray::new(camera_to_world.transform_point(ray.origin),
+                               camera_to_world.transform_vector(ray.direction).normalize());
+        ray.time = time;
+        Some(ray)
+    }
+
+    /// Moves a camera space ray of the center view to the given eye and turns it towards the convergence distance.
+    fn eye_ray(&self, ray: Ray, stereo: &Stereo, eye: f64) -> Ray {
+        let side = match self.projection {
+            // Perpendicular to the horizontal part of the direction, its length fades the eyes together at the poles
+            Projection::Equirectangular => Vector3::new(-ray.direction.z, 0.0, ray.direction.x),
+            _ => Vector3::new(1.0, 0.0, 0.0),
+        };
+        let offset = side * (eye * stereo.interocular_distance * 0.5);
+        if !stereo.convergence_distance.is_finite() {
+            return Ray::new(ray.origin + offset, ray.direction);
         }
+        // Aim at the point the center ray reaches on the convergence plane, or sphere for panoramic projections
+        let reach = match self.projection {
+            Projection::Perspective => stereo.convergence_distance / -ray.direction.z,
+            _ => stereo.convergence_distance,
+        };
+        Ray::new(ray.origin + offset, (ray.direction * reach - offset).normalize())
+    }
+
+    /// World space position of the camera.
+    pub fn position(&self) -> Point3<f64> {
+        self.camera_to_world.transform_point(Point3::origin())
+    }
+
+    /// World space direction the camera looks in.
+    pub fn forward(&self) -> Vector3<f64> {
+        self.camera_to_world.transform_vector(Vector3::new(0.0, 0.0, -1.0)).normalize()
     }
 }
 
+/// Camera space ray leaving the center at `theta` from the viewing direction, towards the image point `(u, v)`
+/// at distance `r` from the image center.
+fn fisheye_ray(u: f64, v: f64, r: f64, theta: f64) -> Ray {
+    let (cos_phi, sin_phi) = if r > 0.0 { (u / r, v / r) } else { (1.0, 0.0) };
+    Ray::new(Point3::origin(), Vector3::new(theta.sin() * cos_phi, theta.sin() * sin_phi, -theta.cos()))
+}
+
+/// Uniform point on the unit aperture, a regular polygon with `blades` corners or a disk for fewer than three.
+fn sample_aperture(u: Vector2<f64>, blades: u32, rotation: f64) -> Vector2<f64> {
+    if blades < 3 {
+        let r = u.x.sqrt();
+        let phi = 2.0 * PI * u.y;
+        return Vector2::new(r * phi.cos(), r * phi.sin());
+    }
+    // Pick one of the equal triangles between the center and an edge, then a uniform point inside it
+    let blade = ((u.x * blades as f64) as u32).min(blades - 1);
+    let u0 = u.x * blades as f64 - blade as f64;
+    let step = 2.0 * PI / blades as f64;
+    let angle = rotation.to_radians() + blade as f64 * step;
+    let a = Vector2::new(angle.cos(), angle.sin());
+    let b = Vector2::new((angle + step).cos(), (angle + step).sin());
+    let s = u0.sqrt();
+    a * (s * (1.0 - u.y)) + b * (s * u.y)
+}
+
 impl Node for CameraNode {
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        let strong = &self.parent.upgrade();
-        let strong = match strong {
-            Some(x) => x,
-            None => return None,
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
+
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
+    }
+
+    fn get_camera(&self) -> Option<CompiledCamera> {
+        let mut camera = CompiledCamera {
+            name: self.core.name.clone(),
+            camera_to_world: self.core.world_transform * self.view_orientation(),
+            image_width: self.image_width,
+            image_height: self.image_height,
+            fov: self.fov,
+            projection: self.projection.clone(),
+            stereo: self.stereo.clone(),
+            aperture_radius: 0.0,
+            focus_distance: 1.0,
+            focus_node: None,
+            blades: 0,
+            blade_rotation: 0.0,
+            shutter_open: self.shutter_open,
+            shutter_close: self.shutter_close,
+            motion: None,
+        };
+        if let Some(ref lens) = self.lens {
+            camera.fov = lens.fov(self.image_width as f64 / self.image_height as f64);
+            camera.aperture_radius = lens.aperture_radius();
+            camera.blades = lens.blades;
+            camera.blade_rotation = lens.blade_rotation;
+            match lens.focus {
+                Focus::Distance(distance) => camera.focus_distance = distance,
+                Focus::Node(ref name) => camera.focus_node = Some(name.clone()),
+            }
+        }
+        return Some(camera);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Scene, Node3D};
+    use crate::compiled_scene::CompiledScene;
+    use crate::node::add_child;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    fn camera(width: usize, height: usize) -> CameraNode {
+        CameraNode::new("Camera".to_string(), Matrix4::identity(), Vector3::new(0.0, 0.0, -1.0), width, height)
+    }
+
+    /// Point where `ray` crosses the plane `distance` in front of the camera.
+    fn at_depth(ray: &Ray, distance: f64) -> Point3<f64> {
+        ray.origin + ray.direction * ((-distance - ray.origin.z) / ray.direction.z)
+    }
+
+    fn direction(camera: &CompiledCamera, x: f64, y: f64) -> Vector3<f64> {
+        camera.generate_ray(x, y, &mut Rng::new(0)).unwrap().direction
+    }
+
+    fn assert_direction(actual: Vector3<f64>, expected: Vector3<f64>) {
+        assert!((actual - expected.normalize()).magnitude() < 1e-9, "{:?} != {:?}", actual, expected.normalize());
+    }
+
+    #[test]
+    fn perspective_and_orthographic_rays() {
+        let mut node = camera(64, 48);
+        node.fov = 60.0;
+        let perspective = node.get_camera().unwrap();
+        let tan = 30f64.to_radians().tan();
+        assert_direction(direction(&perspective, 32.0, 24.0), Vector3::new(0.0, 0.0, -1.0));
+        assert_direction(direction(&perspective, 0.0, 0.0), Vector3::new(-tan * 64.0 / 48.0, tan, -1.0));
+        assert_direction(direction(&perspective, 64.0, 48.0), Vector3::new(tan * 64.0 / 48.0, -tan, -1.0));
+
+        node.projection = Projection::Orthographic { width: 4.0 };
+        let orthographic = node.get_camera().unwrap();
+        for &(x, y, origin) in &[(32.0, 24.0, Point3::new(0.0, 0.0, 0.0)), (0.0, 0.0, Point3::new(-2.0, 1.5, 0.0))] {
+            let ray = orthographic.generate_ray(x, y, &mut Rng::new(0)).unwrap();
+            assert!((ray.origin - origin).magnitude() < 1e-9);
+            assert_direction(ray.direction, Vector3::new(0.0, 0.0, -1.0));
+        }
+    }
+
+    #[test]
+    fn fisheye_rays() {
+        let mut node = camera(64, 64);
+        // Angle from the viewing direction halfway out to the rim of the image circle
+        let equisolid = 2.0 * (0.5 * 45f64.to_radians().sin()).asin().to_degrees();
+        let projections = vec![(Projection::EquidistantFisheye { fov: 180.0 }, 45.0),
+                               (Projection::EquisolidFisheye { fov: 180.0 }, equisolid)];
+        for (projection, halfway) in projections {
+            node.projection = projection;
+            let fisheye = node.get_camera().unwrap();
+            assert_direction(direction(&fisheye, 32.0, 32.0), Vector3::new(0.0, 0.0, -1.0));
+            // The rim of the image circle looks sideways, the image corners lie outside of it
+            assert_direction(direction(&fisheye, 64.0, 32.0), Vector3::new(1.0, 0.0, 0.0));
+            assert_direction(direction(&fisheye, 32.0, 0.0), Vector3::new(0.0, 1.0, 0.0));
+            assert!(fisheye.generate_ray(0.5, 0.5, &mut Rng::new(0)).is_none());
+            let halfway = halfway.to_radians();
+            assert_direction(direction(&fisheye, 48.0, 32.0), Vector3::new(halfway.sin(), 0.0, -halfway.cos()));
+        }
+    }
+
+    #[test]
+    fn equirectangular_rays() {
+        let mut node = camera(64, 32);
+        node.projection = Projection::Equirectangular;
+        let panorama = node.get_camera().unwrap();
+        assert_direction(direction(&panorama, 32.0, 16.0), Vector3::new(0.0, 0.0, -1.0));
+        assert_direction(direction(&panorama, 48.0, 16.0), Vector3::new(1.0, 0.0, 0.0));
+        assert_direction(direction(&panorama, 16.0, 16.0), Vector3::new(-1.0, 0.0, 0.0));
+        assert_direction(direction(&panorama, 0.0, 16.0), Vector3::new(0.0, 0.0, 1.0));
+        assert_direction(direction(&panorama, 32.0, 0.0), Vector3::new(0.0, 1.0, 0.0));
+        assert_direction(direction(&panorama, 32.0, 8.0), Vector3::new(0.0, 1.0, -1.0));
+    }
+
+    fn eye_rays(camera: &CompiledCamera, x: f64, y: f64) -> (Ray, Ray) {
+        let (width, height) = (camera.image_width as f64, camera.image_height as f64);
+        let (right_x, right_y) = match camera.stereo.as_ref().unwrap().layout {
+            StereoLayout::SideBySide => (x + width, y),
+            StereoLayout::OverUnder => (x, y + height),
         };
-        return Some(Rc::clone(&(*strong))); //Some(Rc::clone(&(*(&self.parent))));
+        (camera.generate_ray(x, y, &mut Rng::new(0)).unwrap(), camera.generate_ray(right_x, right_y, &mut Rng::new(0)).unwrap())
     }
 
-    fn get_child(&self, index: usize) -> Option<Rc<RefCell<Node>>> {
-        if index >= self.size {
-            return None;
+    #[test]
+    fn stereo_eyes_sit_apart_and_converge() {
+        let mut node = camera(64, 48);
+        node.stereo = Some(Stereo::new(StereoLayout::SideBySide));
+        let parallel = node.get_camera().unwrap();
+        assert_eq!(parallel.output_size(), (128, 48));
+        let (left, right) = eye_rays(&parallel, 10.5, 20.5);
+        assert!((left.origin - Point3::new(-0.0325, 0.0, 0.0)).magnitude() < 1e-12);
+        assert!((right.origin - Point3::new(0.0325, 0.0, 0.0)).magnitude() < 1e-12);
+        assert_direction(left.direction, right.direction);
+
+        node.stereo = Some(Stereo { convergence_distance: 2.0, ..Stereo::new(StereoLayout::OverUnder) });
+        let converged = node.get_camera().unwrap();
+        assert_eq!(converged.output_size(), (64, 96));
+        node.stereo = None;
+        let center = node.get_camera().unwrap();
+        for &(x, y) in &[(32.0, 24.0), (10.5, 20.5), (60.0, 2.0)] {
+            let (left, right) = eye_rays(&converged, x, y);
+            let target = at_depth(&center.generate_ray(x, y, &mut Rng::new(0)).unwrap(), 2.0);
+            assert!((at_depth(&left, 2.0) - target).magnitude() < 1e-9);
+            assert!((at_depth(&right, 2.0) - target).magnitude() < 1e-9);
         }
-        return Some(Rc::clone(&(self.childs[index])));
     }
 
-    fn add_child(&mut self, node: Rc<RefCell<Node>>) {
-        self.size += 1;
-        value!(node).set_world_transform(&self.world_transform);
-        self.childs.push(Rc::clone(&node));
-    } 
+    #[test]
+    fn panorama_eyes_circle_the_camera() {
+        let mut node = camera(64, 32);
+        node.projection = Projection::Equirectangular;
+        node.stereo = Some(Stereo::new(StereoLayout::OverUnder));
+        let ods = node.get_camera().unwrap();
+        for &x in &[0.5, 10.5, 32.0, 47.25, 63.5] {
+            // On the horizon the eyes sit on the interpupillary circle, across the viewing direction
+            let (left, right) = eye_rays(&ods, x, 16.0);
+            assert!((left.origin.to_vec().magnitude() - 0.0325).abs() < 1e-12);
+            assert!((left.origin.to_vec() + right.origin.to_vec()).magnitude() < 1e-12);
+            assert!(left.origin.y.abs() < 1e-12);
+            assert!(left.origin.to_vec().dot(left.direction).abs() < 1e-12);
+            // The left eye is on the left when looking along the ray
+            assert!(left.direction.cross(left.origin.to_vec()).y > 0.0);
+            assert_direction(left.direction, right.direction);
+        }
+        // Towards the poles the eyes move together
+        let (left, _) = eye_rays(&ods, 20.0, 0.5);
+        assert!(left.origin.to_vec().magnitude() < 0.0325 * 0.1);
 
-    fn get_size(&self) -> usize {
-        return self.size;
+        node.stereo = Some(Stereo { convergence_distance: 3.0, ..Stereo::new(StereoLayout::SideBySide) });
+        let converged = node.get_camera().unwrap();
+        node.stereo = None;
+        let center = node.get_camera().unwrap();
+        for &(x, y) in &[(10.5, 16.0), (40.0, 10.0)] {
+            let target = Point3::origin() + direction(&center, x, y) * 3.0;
+            let (left, right) = eye_rays(&converged, x, y);
+            for ray in &[left, right] {
+                let to_target = target - ray.origin;
+                assert!(to_target.dot(ray.direction) > 0.0);
+                assert!((to_target.normalize() - ray.direction).magnitude() < 1e-9);
+            }
+        }
     }
 
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vector3<f64>)> {
-        return None;
+    #[test]
+    fn lens_rays_meet_on_the_focus_plane() {
+        let mut node = camera(64, 48);
+        node.lens = Some(ThinLens::new(50.0, 1.4, Focus::Distance(5.0)));
+        let lensed = node.get_camera().unwrap();
+        node.lens = None;
+        node.fov = lensed.fov;
+        let pinhole = node.get_camera().unwrap();
+
+        let mut rng = Rng::new(7);
+        for &(x, y) in &[(32.0, 24.0), (0.5, 0.5), (50.25, 40.75)] {
+            let target = at_depth(&pinhole.generate_ray(x, y, &mut rng).unwrap(), 5.0);
+            let mut spread = 0.0f64;
+            for _ in 0..64 {
+                let ray = lensed.generate_ray(x, y, &mut rng).unwrap();
+                assert!(ray.origin.z.abs() < 1e-12);
+                assert!(ray.origin.to_vec().magnitude() <= lensed.aperture_radius + 1e-12);
+                assert!((at_depth(&ray, 5.0) - target).magnitude() < 1e-9);
+                // Off the focus plane the rays spread over the aperture
+                spread = spread.max((at_depth(&ray, 10.0) - at_depth(&pinhole.generate_ray(x, y, &mut rng).unwrap(), 10.0)).magnitude());
+            }
+            assert!(spread > lensed.aperture_radius * 0.5);
+        }
     }
 
-    fn get_color(&self) -> Color {
-        return Color::new(0.0, 0.0, 0.0);
+    #[test]
+    fn blade_samples_stay_inside_the_polygon() {
+        let (blades, rotation) = (5, 20.0f64);
+        let step = 2.0 * PI / blades as f64;
+        let corner = |i: u32| {
+            let angle = rotation.to_radians() + i as f64 * step;
+            Vector2::new(angle.cos(), angle.sin())
+        };
+        let mut rng = Rng::new(3);
+        let mut farthest = 0.0f64;
+        for _ in 0..4096 {
+            let p = sample_aperture(rng.next_2d(), blades, rotation);
+            for i in 0..blades {
+                let (a, b) = (corner(i), corner(i + 1));
+                let edge = b - a;
+                let to_p = p - a;
+                assert!(edge.x * to_p.y - edge.y * to_p.x >= -1e-12, "{:?} is outside edge {}", p, i);
+            }
+            farthest = farthest.max(p.magnitude());
+            assert!(sample_aperture(rng.next_2d(), 0, 0.0).magnitude() <= 1.0);
+        }
+        // Samples reach out towards the corners
+        assert!(farthest > 0.95);
     }
 
-    fn get_world_transform(&self) -> Matrix4<f64> {
-        return self.world_transform;
+    #[test]
+    fn focus_node_sets_the_focus_distance() {
+        let root: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new("root".to_string(), Matrix4::identity())));
+        let mut node = CameraNode::new("Camera".to_string(), Matrix4::from_translation(Vector3::new(0.0, 1.0, 2.0)),
+                                       Vector3::new(0.0, 0.0, -1.0), 32, 32);
+        node.lens = Some(ThinLens::new(50.0, 2.0, Focus::Node("Target".to_string())));
+        add_child(&root, Rc::new(RefCell::new(node)));
+        add_child(&root, Rc::new(RefCell::new(Node3D::new("Target".to_string(),
+            Matrix4::from_translation(Vector3::new(3.0, 1.0, -5.0))))));
+        let compiled = CompiledScene::new(&Scene::new("scene".to_string(), root)).unwrap();
+        assert!((compiled.cameras[0].focus_distance - 7.0).abs() < 1e-9);
     }
 
-    fn set_world_transform(&mut self, transform: &Matrix4<f64>) -> () {
-        self.world_transform = transform * self.frame_transform;
+    #[test]
+    fn missing_focus_node_is_an_error() {
+        let root: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new("root".to_string(), Matrix4::identity())));
+        let mut node = camera(32, 32);
+        node.lens = Some(ThinLens::new(50.0, 2.0, Focus::Node("Nowhere".to_string())));
+        add_child(&root, Rc::new(RefCell::new(node)));
+        let error = CompiledScene::new(&Scene::new("scene".to_string(), root)).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,136 @@
+use crate::{Node, Color, Ray, Material, HitRecord, Aabb, Shape};
+use crate::compiled_scene::SceneCompiler;
+use crate::node::NodeCore;
+use crate::cylinder_node::side_frame;
+use crate::polynomial::solve_quadratic;
+use std::sync::Arc;
+use cgmath::{InnerSpace, Vector3, Point3, Matrix4};
+use std::f64::consts::PI;
+
+/// Cylinder around the local y axis closed by two hemispheres, centered on the local origin.
+#[derive(Clone)]
+pub struct Capsule {
+    pub radius: f64,
+    /// Length of the cylindrical part, the capsule is `height + 2 * radius` tall.
+    pub height: f64,
+}
+
+/// Scene node placing a `Capsule`.
+pub struct CapsuleNode {
+    pub core: NodeCore,
+    pub shape: Arc<Capsule>,
+    pub material: Arc<Material>,
+}
+
+impl CapsuleNode {
+    pub fn new(name: String, transform: Matrix4<f64>, radius: f64, height: f64, color: Color) -> CapsuleNode {
+        CapsuleNode {
+            core: NodeCore::new(name, transform),
+            shape: Arc::new(Capsule {
+                radius: radius,
+                height: height,
+            }),
+            material: Arc::new(Material::from_color(color)),
+        }
+    }
+}
+
+impl Capsule {
+    fn surface_hit(&self, ray: &Ray, distance: f64) -> HitRecord {
+        let half = self.height * 0.5;
+        let p = ray.origin + ray.direction * distance;
+        let axis = Point3::new(0.0, p.y.clamp(-half, half), 0.0);
+        let normal = (p - axis).normalize();
+
+        // v follows the arc length of the profile from the bottom pole to the top pole
+        let length = self.height + PI * self.radius;
+        let latitude = normal.y.clamp(-1.0, 1.0).asin();
+        let arc = self.radius * (latitude + 0.5 * PI) + axis.y + half;
+        let (mut uv, dpdu) = side_frame(p.x, p.y, p.z, half);
+        uv.y = arc / length;
+
+        let rho = (normal.x * normal.x + normal.z * normal.z).sqrt();
+        let dpdv = if rho > 1e-9 {
+            Vector3::new(-normal.y * normal.x / rho, rho, -normal.y * normal.z / rho) * length
+        } else {
+            // At the poles the longitude is undefined, any tangent direction will do
+            Vector3::new(length, 0.0, 0.0)
+        };
+        let mut hit = HitRecord::new(ray, distance, normal, uv, dpdu, dpdv, None);
+        // Reproject onto the surface, the solved distance is less accurate than the error bound assumes
+        hit.position = axis + normal * self.radius;
+        hit
+    }
+}
+
+impl Shape for Capsule {
+    fn intersect_all(&self, ray: &Ray) -> Vec<HitRecord> {
+        let (o, d) = (ray.origin, ray.direction);
+        let half = self.height * 0.5;
+        let r2 = self.radius * self.radius;
+        let mut distances = vec![];
+
+        let a = d.x * d.x + d.z * d.z;
+        if a > 0.0 {
+            if let Some((t0, t1)) = solve_quadratic(a, 2.0 * (o.x * d.x + o.z * d.z), o.x * o.x + o.z * o.z - r2) {
+                distances.extend([t0, t1].iter().filter(|t| (o.y + d.y * **t).abs() <= half));
+            }
+        }
+
+        // Each hemisphere only keeps the hits beyond the end of the cylinder it closes
+        for side in [-1.0, 1.0].iter() {
+            let center = Point3::new(0.0, side * half, 0.0);
+            let oc = o - center;
+            if let Some((t0, t1)) = solve_quadratic(d.dot(d), 2.0 * oc.dot(d), oc.dot(oc) - r2) {
+                distances.extend([t0, t1].iter().filter(|t| (oc.y + d.y * **t) * side > 0.0));
+            }
+        }
+
+        distances.retain(|t| ray.contains(*t));
+        distances.sort_by(|a, b| a.total_cmp(b));
+        distances.into_iter().map(|t| self.surface_hit(ray, t)).collect()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let half_extents = Vector3::new(self.radius, self.height * 0.5 + self.radius, self.radius);
+        Some(Aabb::centered(half_extents))
+    }
+}
+
+impl Node for CapsuleNode {
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
+
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
+    }
+
+    fn get_shape(&self, _compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        return Some(self.shape.clone());
+    }
+
+    fn get_material(&self) -> Option<Arc<Material>> {
+        return Some(Arc::clone(&self.material));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Point3, InnerSpace};
+
+    #[test]
+    fn axis_aligned_rays_hit_side_and_caps() {
+        let capsule = Capsule { radius: 1.0, height: 2.0 };
+        let side = capsule.intersect_all(&Ray::new(Point3::new(0.0, 0.5, 5.0), Vector3::new(0.0, 0.0, -1.0)));
+        assert_eq!(side.len(), 2);
+        assert!((side[0].distance - 4.0).abs() < 1e-9 && (side[1].distance - 6.0).abs() < 1e-9);
+
+        // The hemispheres reach up to height / 2 + radius
+        let caps = capsule.intersect_all(&Ray::new(Point3::new(0.0, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0)));
+        assert_eq!(caps.len(), 2);
+        assert!((caps[0].distance - 3.0).abs() < 1e-9 && (caps[1].distance - 7.0).abs() < 1e-9);
+        assert!((caps[0].geometric_normal - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-9);
+    }
+}
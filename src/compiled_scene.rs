@@ -0,0 +1,474 @@
+use crate::{Scene, Node, Ray, Material, HitRecord, Aabb, Shape, Bvh, CompiledLight, CompiledCamera, MotionChain};
+use crate::aov::id_from_name;
+use crate::node::{self, update_transforms};
+use cgmath::{Matrix4, SquareMatrix, Vector2, Point3, EuclideanSpace, InnerSpace};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Steps each interval between motion keys is sampled at to bound moving primitives.
+const MOTION_BOUNDS_STEPS: usize = 16;
+
+/// A shape placed in space with its material, the unit the compiled scene is made of.
+pub struct Primitive {
+    pub shape: Arc<dyn Shape>,
+    /// Placement at the start of `motion` for moving primitives.
+    pub world_transform: Matrix4<f64>,
+    pub inverse_transform: Matrix4<f64>,
+    /// Placement over time for motion blur, `None` for static primitives.
+    pub motion: Option<MotionChain>,
+    /// Replaces the materials of the shape's hits if set.
+    pub material: Option<Arc<Material>>,
+    pub object_id: u32,
+    bounds: Option<Aabb>,
+}
+
+impl Primitive {
+    pub fn new(shape: Arc<dyn Shape>, world_transform: Matrix4<f64>, material: Option<Arc<Material>>, name: &str)
+               -> Primitive {
+        let bounds = shape.bounds().map(|bounds| bounds.transform(&world_transform));
+        Primitive {
+            shape: shape,
+            world_transform: world_transform,
+            inverse_transform: world_transform.invert().unwrap_or(Matrix4::identity()),
+            motion: None,
+            material: material,
+            object_id: id_from_name(name),
+            bounds: bounds,
+        }
+    }
+
+    /// Moves the primitive along `motion`, its bounds grow to cover the whole path.
+    pub fn with_motion(mut self, motion: MotionChain) -> Primitive {
+        let start = motion.at(motion.times()[0]);
+        self.world_transform = start;
+        self.inverse_transform = start.invert().unwrap_or(Matrix4::identity());
+        if let Some(local) = self.shape.bounds() {
+            let mut bounds = local.transform(&start);
+            for pair in motion.times().windows(2) {
+                for step in 1..=MOTION_BOUNDS_STEPS {
+                    let time = pair[0] + (pair[1] - pair[0]) * step as f64 / MOTION_BOUNDS_STEPS as f64;
+                    bounds = bounds.union(&local.transform(&motion.at(time)));
+                }
+            }
+            self.bounds = Some(bounds);
+        }
+        self.motion = Some(motion);
+        self
+    }
+
+    /// Bounds in the space the primitive is placed in, `None` if it is unbounded.
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.bounds
+    }
+
+    /// World and inverse transform at `time`.
+    pub fn transforms_at(&self, time: f64) -> (Matrix4<f64>, Matrix4<f64>) {
+        match self.motion {
+            Some(ref motion) => {
+                let world = motion.at(time);
+                (world, world.invert().unwrap_or(Matrix4::identity()))
+            }
+            None => (self.world_transform, self.inverse_transform),
+        }
+    }
+
+    fn place(&self, hit: HitRecord, world: &Matrix4<f64>, inverse: &Matrix4<f64>) -> HitRecord {
+        let mut hit = hit.to_world(world, inverse);
+        if let Some(ref material) = self.material {
+            hit.material = Some(Arc::clone(material));
+        }
+        hit
+    }
+
+    pub fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        let (world, inverse) = self.transforms_at(ray.time);
+        let local_ray = ray.transform(&inverse);
+        self.shape.intersect(&local_ray).map(|hit| self.place(hit, &world, &inverse))
+    }
+
+    pub fn intersect_all(&self, ray: &Ray) -> Vec<HitRecord> {
+        let (world, inverse) = self.transforms_at(ray.time);
+        let local_ray = ray.transform(&inverse);
+        self.shape.intersect_all(&local_ray).into_iter().map(|hit| self.place(hit, &world, &inverse)).collect()
+    }
+}
+
+/// Primitives under a bounding volume hierarchy, both the whole scene and the prototypes of instances.
+pub struct Group {
+    pub primitives: Vec<Primitive>,
+    bvh: Bvh,
+}
+
+impl Group {
+    pub fn new(primitives: Vec<Primitive>) -> Group {
+        let bounds: Vec<Option<Aabb>> = primitives.iter().map(|primitive| primitive.bounds()).collect();
+        Group {
+            bvh: Bvh::new(&bounds),
+            primitives: primitives,
+        }
+    }
+
+    /// Nearest hit and the primitive it belongs to.
+    pub fn intersect_primitive(&self, ray: &Ray) -> Option<(HitRecord, &Primitive)> {
+        let mut nearest: Option<(HitRecord, &Primitive)> = None;
+        let mut closest = ray.t_max;
+        self.bvh.traverse(ray, |index| {
+            let ray = Ray {
+                origin: ray.origin,
+                direction: ray.direction,
+                t_min: ray.t_min,
+                t_max: closest,
+                time: ray.time,
+            };
+            let primitive = &self.primitives[index];
+            if let Some(hit) = primitive.intersect(&ray) {
+                closest = hit.distance;
+                nearest = Some((hit, primitive));
+            }
+            closest
+        });
+        nearest
+    }
+}
+
+impl Shape for Group {
+    fn intersect_all(&self, ray: &Ray) -> Vec<HitRecord> {
+        let mut hits = vec![];
+        self.bvh.traverse(ray, |index| {
+            hits.extend(self.primitives[index].intersect_all(ray));
+            ray.t_max
+        });
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        self.intersect_primitive(ray).map(|(hit, _)| hit)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.bvh.bounds()
+    }
+}
+
+/// Flat, immutable snapshot of a `Scene` with world transforms, materials and lights baked in.
+///
+/// Lights are gathered from every node of the graph, lights inside instance prototypes are not instanced.
+///
+/// Unlike the node tree it can be shared between render threads.
+pub struct CompiledScene {
+    pub geometry: Group,
+    pub lights: Vec<CompiledLight>,
+    /// Indices of the primitives with an emissive material whose shape can be sampled, they light the scene
+    /// like the lights do. Emissive parts of instances and CSG nodes glow but light nothing.
+    pub emitters: Vec<usize>,
+    pub cameras: Vec<CompiledCamera>,
+    /// Index of the scene's active camera in `cameras`.
+    pub active_camera: Option<usize>,
+}
+
+impl CompiledScene {
+    /// Fails with `NotFound` if a camera's lens focuses on a node that is not in the scene.
+    pub fn new(scene: &Scene) -> io::Result<CompiledScene> {
+        update_transforms(&scene.root);
+        let mut compiler = SceneCompiler::new();
+        let mut primitives = vec![];
+        compiler.compile_subtree(&scene.root, &mut primitives);
+        let mut lights = vec![];
+        let mut cameras = vec![];
+        node::visit(&scene.root, &mut |node, _| {
+            let motion = world_motion(node);
+            let node = value!(node);
+            // Lights stay where they are at the start of their motion
+            lights.extend(node.get_light());
+            if let Some(mut camera) = node.get_camera() {
+                if let Some(mut motion) = motion {
+                    // Keep the camera's own orientation on top of the moving node
+                    let view = node.get_world_transform().invert().unwrap_or(Matrix4::identity()) * camera.camera_to_world;
+                    motion.push(view, None);
+                    camera.motion = Some(motion);
+                }
+                cameras.push(camera);
+            }
+        });
+        for camera in cameras.iter_mut() {
+            let focus = match camera.focus_node {
+                Some(ref name) => node::find_by_name(&scene.root, name).ok_or_else(|| io::Error::new(
+                    io::ErrorKind::NotFound, format!("camera {} focuses on {}, which is not in scene {}",
+                                                     camera.name, name, scene.name)))?,
+                None => continue,
+            };
+            let target = Point3::from_vec(value!(focus).get_world_transform().w.truncate());
+            camera.focus_distance = (target - camera.position()).dot(camera.forward()).max(1e-3);
+        }
+        let active_camera = match scene.active_camera {
+            Some(ref name) => cameras.iter().position(|camera| camera.name == *name),
+            None if !cameras.is_empty() => Some(0),
+            None => None,
+        };
+        let emitters = primitives.iter().enumerate()
+            .filter(|(_, primitive)| primitive.material.as_ref().is_some_and(|material| material.is_emissive()))
+            .filter(|(_, primitive)| primitive.shape.sample(Vector2::new(0.5, 0.5)).is_some())
+            .map(|(index, _)| index)
+            .collect();
+        Ok(CompiledScene {
+            geometry: Group::new(primitives),
+            lights: lights,
+            emitters: emitters,
+            cameras: cameras,
+            active_camera: active_camera,
+        })
+    }
+
+    /// Camera named `name`, or the active camera if `name` is `None`.
+    pub fn camera(&self, name: Option<&str>) -> Option<&CompiledCamera> {
+        match name {
+            Some(name) => self.cameras.iter().find(|camera| camera.name == name),
+            None => self.active_camera.map(|index| &self.cameras[index]),
+        }
+    }
+
+    pub fn intersect(&self, ray: &Ray) -> Option<(HitRecord, &Primitive)> {
+        self.geometry.intersect_primitive(ray)
+    }
+}
+
+/// Turns nodes into primitives, shared prototypes are compiled only once.
+pub struct SceneCompiler {
+    prototypes: HashMap<*const RefCell<dyn Node>, Arc<Group>>,
+}
+
+impl SceneCompiler {
+    fn new() -> SceneCompiler {
+        SceneCompiler {
+            prototypes: HashMap::new(),
+        }
+    }
+
+    /// Primitive for `node` alone placed in world space.
+    pub fn compile_node(&mut self, node: &Rc<RefCell<dyn Node>>) -> Option<Primitive> {
+        let motion = world_motion(node);
+        let node = value!(node);
+        let shape = node.get_shape(self)?;
+        let primitive = Primitive::new(shape, node.get_world_transform(), node.get_material(), &node.get_name());
+        Some(match motion {
+            Some(motion) => primitive.with_motion(motion),
+            None => primitive,
+        })
+    }
+
+    /// Primitives for `node` and everything below it placed in the local space of the parent of `node`, for
+    /// shapes made of their children.
+    pub fn compile_local_subtree(&mut self, node: &Rc<RefCell<dyn Node>>, primitives: &mut Vec<Primitive>) {
+        self.compile_chain(&mut vec![Rc::clone(node)], primitives);
+    }
+
+    /// Compiles the last node of `chain` and its subtree, `chain` runs down from the node whose parent's space
+    /// the primitives are placed in.
+    fn compile_chain(&mut self, chain: &mut Vec<Rc<RefCell<dyn Node>>>, primitives: &mut Vec<Primitive>) {
+        let node = Rc::clone(chain.last().unwrap());
+        let transform = chain.iter().fold(Matrix4::identity(), |transform, x| transform * value!(x).get_frame_transform());
+        let motion = chain_motion(chain);
+        let includes_children = {
+            let node = value!(node);
+            if let Some(shape) = node.get_shape(self) {
+                let primitive = Primitive::new(shape, transform, node.get_material(), &node.get_name());
+                primitives.push(match motion {
+                    Some(motion) => primitive.with_motion(motion),
+                    None => primitive,
+                });
+            }
+            node.shape_includes_children()
+        };
+        if includes_children {
+            return;
+        }
+        let size = value!(node).get_size();
+        for i in 0..size {
+            let child = value!(node).get_child(i);
+            if let Some(child) = child {
+                chain.push(child);
+                self.compile_chain(chain, primitives);
+                chain.pop();
+            }
+        }
+    }
+
+    /// Primitives for `node` and everything below it, see `compile_node`.
+    pub fn compile_subtree(&mut self, node: &Rc<RefCell<dyn Node>>, primitives: &mut Vec<Primitive>) {
+        primitives.extend(self.compile_node(node));
+        if value!(node).shape_includes_children() {
+            return;
+        }
+        let size = value!(node).get_size();
+        for i in 0..size {
+            let child = value!(node).get_child(i);
+            if let Some(child) = child {
+                self.compile_subtree(&child, primitives);
+            }
+        }
+    }
+
+    /// The subtree below `prototype` as a group in the prototype's world space.
+    pub fn prototype(&mut self, prototype: &Rc<RefCell<dyn Node>>) -> Arc<Group> {
+        let key = Rc::as_ptr(prototype);
+        if let Some(group) = self.prototypes.get(&key) {
+            return Arc::clone(group);
+        }
+        update_transforms(prototype);
+        let mut primitives = vec![];
+        self.compile_subtree(prototype, &mut primitives);
+        let group = Arc::new(Group::new(primitives));
+        self.prototypes.insert(key, Arc::clone(&group));
+        group
+    }
+}
+
+/// World space motion of `node`, `None` if neither it nor any of its ancestors moves.
+fn world_motion(node: &Rc<RefCell<dyn Node>>) -> Option<MotionChain> {
+    chain_motion(&node::chain_from_root(node))
+}
+
+/// Motion of the last node of `chain` relative to the parent of the first, `None` if none of them moves.
+fn chain_motion(chain: &[Rc<RefCell<dyn Node>>]) -> Option<MotionChain> {
+    let mut motion = MotionChain::default();
+    for node in chain {
+        let node = value!(node);
+        motion.push(node.get_frame_transform(), node.core().frame_motion.as_ref());
+    }
+    if motion.times().is_empty() {
+        return None;
+    }
+    Some(motion)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Node3D, SphereNode, SphericalLight, Color, Motion};
+    use crate::box_node::BoxNode;
+    use crate::light::{DirectionalLight, LightSource};
+    use crate::node::{add_child, remove_child};
+    use cgmath::{Vector3, Deg};
+
+    fn group(name: &str, transform: Matrix4<f64>) -> Rc<RefCell<dyn Node>> {
+        Rc::new(RefCell::new(Node3D::new(name.to_string(), transform)))
+    }
+
+    fn sphere(name: &str, transform: Matrix4<f64>) -> Rc<RefCell<dyn Node>> {
+        Rc::new(RefCell::new(SphereNode::new(name.to_string(), transform, 1.0, Color::new(1.0, 1.0, 1.0))))
+    }
+
+    fn assert_matrix_eq(a: Matrix4<f64>, b: Matrix4<f64>) {
+        let (a, b): (&[f64; 16], &[f64; 16]) = (a.as_ref(), b.as_ref());
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() < 1e-9, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn nested_transforms_end_up_in_the_world_matrices() {
+        let outer = Matrix4::from_translation(Vector3::new(0.0, 0.0, -10.0)) * Matrix4::from_angle_y(Deg(90.0));
+        let inner = Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0);
+        let leaf = Matrix4::from_translation(Vector3::new(3.0, 0.0, 0.0));
+        let root = group("root", Matrix4::identity());
+        let (outer_group, inner_group, ball) = (group("outer", outer), group("inner", inner), sphere("ball", leaf));
+        add_child(&inner_group, Rc::clone(&ball));
+        add_child(&outer_group, Rc::clone(&inner_group));
+        add_child(&root, Rc::clone(&outer_group));
+        // Changed after attaching, compilation has to bring it up to date
+        value!(inner_group).set_frame_transform(&Matrix4::from_nonuniform_scale(1.0, 1.0, 0.5));
+        let compiled = CompiledScene::new(&Scene::new("scene".to_string(), Rc::clone(&root))).unwrap();
+
+        let primitives = &compiled.geometry.primitives;
+        assert_eq!(primitives.len(), 1);
+        let expected = outer * Matrix4::from_nonuniform_scale(1.0, 1.0, 0.5) * leaf;
+        assert_matrix_eq(primitives[0].world_transform, expected);
+        assert_matrix_eq(primitives[0].inverse_transform, expected.invert().unwrap());
+        // The sphere sits at (0, 0, -13) squashed to half its size along x
+        let (hit, _) = compiled.intersect(&Ray::new(Point3::new(5.0, 0.0, -13.0), Vector3::new(-1.0, 0.0, 0.0))).unwrap();
+        assert!((hit.distance - 4.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn removed_nodes_are_not_compiled() {
+        let root = group("root", Matrix4::identity());
+        let kept_group = group("kept", Matrix4::identity());
+        let removed_group = group("removed", Matrix4::identity());
+        add_child(&kept_group, sphere("kept ball", Matrix4::identity()));
+        add_child(&removed_group, sphere("removed ball", Matrix4::from_translation(Vector3::new(5.0, 0.0, 0.0))));
+        add_child(&root, Rc::clone(&kept_group));
+        add_child(&root, Rc::clone(&removed_group));
+        assert!(remove_child(&root, &removed_group));
+        let compiled = CompiledScene::new(&Scene::new("scene".to_string(), root)).unwrap();
+
+        assert_eq!(compiled.geometry.primitives.len(), 1);
+        assert_eq!(compiled.geometry.primitives[0].object_id, id_from_name("kept ball"));
+        assert!(compiled.intersect(&Ray::new(Point3::new(5.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0))).is_none());
+    }
+
+    #[test]
+    fn lights_are_found_at_their_world_placement() {
+        let root = group("root", Matrix4::identity());
+        let outer = group("outer", Matrix4::from_translation(Vector3::new(0.0, 5.0, 0.0)) * Matrix4::from_angle_z(Deg(90.0)));
+        let inner = group("inner", Matrix4::from_scale(2.0));
+        let removed = group("removed", Matrix4::identity());
+        let white = Color::new(1.0, 1.0, 1.0);
+        add_child(&inner, Rc::new(RefCell::new(SphericalLight::new("Lamp".to_string(),
+            Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0)), white.copy(), 1.0))));
+        add_child(&inner, Rc::new(RefCell::new(DirectionalLight::new("Sun".to_string(), Matrix4::identity(),
+            Vector3::new(1.0, 0.0, 0.0), white.copy(), 1.0))));
+        add_child(&removed, Rc::new(RefCell::new(SphericalLight::new("Gone".to_string(), Matrix4::identity(), white, 1.0))));
+        add_child(&outer, Rc::clone(&inner));
+        add_child(&root, Rc::clone(&outer));
+        add_child(&root, Rc::clone(&removed));
+        remove_child(&root, &removed);
+        let compiled = CompiledScene::new(&Scene::new("scene".to_string(), root)).unwrap();
+
+        let names: Vec<&str> = compiled.lights.iter().map(|light| light.name.as_str()).collect();
+        assert_eq!(names, ["Lamp", "Sun"]);
+        match compiled.lights[0].source {
+            LightSource::Spherical(position) => assert!((position - Point3::new(0.0, 7.0, 0.0)).magnitude() < 1e-9),
+            _ => panic!("Lamp should be spherical"),
+        }
+        match compiled.lights[1].source {
+            LightSource::Directional(direction) => assert!((direction - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-9),
+            _ => panic!("Sun should be directional"),
+        }
+    }
+
+    #[test]
+    fn moving_children_keep_the_shear_of_scaled_parents() {
+        let root = group("root", Matrix4::identity());
+        let scale = Matrix4::from_nonuniform_scale(3.0, 1.0, 1.0);
+        let parent = group("parent", scale);
+        let cube: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(BoxNode::new("cube".to_string(), Matrix4::identity(),
+                                                                           Vector3::new(2.0, 2.0, 2.0), Color::new(1.0, 1.0, 1.0))));
+        let offset = Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0));
+        value!(cube).set_frame_motion(Some(Motion::new(vec![
+            (0.0, offset), (1.0, offset * Matrix4::from_angle_z(Deg(90.0)))])));
+        add_child(&parent, Rc::clone(&cube));
+        add_child(&root, Rc::clone(&parent));
+        let compiled = CompiledScene::new(&Scene::new("scene".to_string(), root)).unwrap();
+
+        let primitive = &compiled.geometry.primitives[0];
+        assert_matrix_eq(primitive.world_transform, scale * offset);
+        for &time in &[0.0, 0.25, 0.5, 1.0] {
+            let expected = scale * offset * Matrix4::from_angle_z(Deg(90.0 * time));
+            let (world, inverse) = primitive.transforms_at(time);
+            assert_matrix_eq(world, expected);
+            assert_matrix_eq(inverse, expected.invert().unwrap());
+        }
+        // Halfway the cube stands on an edge and is stretched sideways into a rhombus, the edge stays where it was
+        let mut ray = Ray::new(Point3::new(3.0, 10.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        ray.time = 0.5;
+        let (hit, _) = compiled.intersect(&ray).unwrap();
+        assert!((hit.distance - (10.0 - 2f64.sqrt())).abs() < 1e-9);
+        // The bounds cover the whole sweep
+        let bounds = compiled.geometry.bounds().unwrap();
+        assert!(bounds.min.x <= 0.0 + 1e-9 && bounds.max.x >= 6.0 - 1e-9 && bounds.max.y >= 2f64.sqrt() - 1e-9);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{Node, Color, Ray, Material, HitRecord, Aabb, Shape};
+use crate::compiled_scene::SceneCompiler;
+use crate::node::NodeCore;
+use crate::cylinder_node::{side_frame, cap_frame};
+use crate::polynomial::solve_quadratic;
+use std::sync::Arc;
+use cgmath::{InnerSpace, Vector3, Matrix4};
+
+/// Cone around the local y axis centered on the local origin, the capped base lies at `-height / 2`
+/// and the apex at `height / 2`.
+#[derive(Clone)]
+pub struct Cone {
+    /// Radius of the base.
+    pub radius: f64,
+    pub height: f64,
+}
+
+/// Scene node placing a `Cone`.
+pub struct ConeNode {
+    pub core: NodeCore,
+    pub shape: Arc<Cone>,
+    pub material: Arc<Material>,
+}
+
+impl ConeNode {
+    pub fn new(name: String, transform: Matrix4<f64>, radius: f64, height: f64, color: Color) -> ConeNode {
+        ConeNode {
+            core: NodeCore::new(name, transform),
+            shape: Arc::new(Cone {
+                radius: radius,
+                height: height,
+            }),
+            material: Arc::new(Material::from_color(color)),
+        }
+    }
+}
+
+impl Shape for Cone {
+    fn intersect_all(&self, ray: &Ray) -> Vec<HitRecord> {
+        let (o, d) = (ray.origin, ray.direction);
+        let half = self.height * 0.5;
+        let mut hits = vec![];
+
+        // x^2 + z^2 = (k * s)^2 with s the height below the apex, the roots include the mirrored cone above it
+        let k2 = (self.radius / self.height).powi(2);
+        let s0 = half - o.y;
+        let a = d.x * d.x + d.z * d.z - k2 * d.y * d.y;
+        let b = 2.0 * (o.x * d.x + o.z * d.z + k2 * s0 * d.y);
+        let c = o.x * o.x + o.z * o.z - k2 * s0 * s0;
+        if let Some((t0, t1)) = solve_quadratic(a, b, c) {
+            let roots = if t0 == t1 { vec![t0] } else { vec![t0, t1] };
+            for t in roots {
+                let mut p = o + d * t;
+                if ray.contains(t) && p.y.abs() <= half {
+                    // Reproject onto the surface, the solved distance is less accurate than the error bound assumes
+                    let rho = (p.x * p.x + p.z * p.z).sqrt();
+                    if rho > 0.0 {
+                        let scale = self.radius * (half - p.y) / (self.height * rho);
+                        p.x *= scale;
+                        p.z *= scale;
+                    }
+                    let rho = (p.x * p.x + p.z * p.z).sqrt();
+                    let normal = Vector3::new(p.x, k2 * (half - p.y), p.z);
+                    let normal = if normal.magnitude2() > 0.0 { normal } else { Vector3::new(0.0, 1.0, 0.0) };
+                    let (uv, dpdu) = side_frame(p.x, p.y, p.z, half);
+                    // Up along the slant towards the apex
+                    let dpdv = if rho > 0.0 {
+                        Vector3::new(-p.x * self.radius / rho, self.height, -p.z * self.radius / rho)
+                    } else {
+                        Vector3::new(0.0, self.height, 0.0)
+                    };
+                    let mut hit = HitRecord::new(ray, t, normal, uv, dpdu, dpdv, None);
+                    hit.position = p;
+                    hits.push(hit);
+                }
+            }
+        }
+
+        let t = (-half - o.y) / d.y;
+        let p = o + d * t;
+        if ray.contains(t) && p.x * p.x + p.z * p.z <= self.radius * self.radius {
+            let (uv, dpdu, dpdv) = cap_frame(p.x, p.z, self.radius, false);
+            let mut hit = HitRecord::new(ray, t, Vector3::new(0.0, -1.0, 0.0), uv, dpdu, dpdv, None);
+            hit.position.y = -half;
+            hits.push(hit);
+        }
+
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let half_extents = Vector3::new(self.radius, self.height * 0.5, self.radius);
+        Some(Aabb::centered(half_extents))
+    }
+}
+
+impl Node for ConeNode {
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
+
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
+    }
+
+    fn get_shape(&self, _compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        return Some(self.shape.clone());
+    }
+
+    fn get_material(&self) -> Option<Arc<Material>> {
+        return Some(Arc::clone(&self.material));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Point3, InnerSpace};
+
+    #[test]
+    fn axis_aligned_rays_hit_side_and_base() {
+        let cone = Cone { radius: 1.0, height: 2.0 };
+        // Halfway up the radius is 0.5
+        let side = cone.intersect_all(&Ray::new(Point3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0)));
+        assert_eq!(side.len(), 2);
+        assert!((side[0].distance - 4.5).abs() < 1e-9 && (side[1].distance - 5.5).abs() < 1e-9);
+        assert!(side[0].front_face && side[0].geometric_normal.z > 0.0 && side[0].geometric_normal.y > 0.0);
+
+        let base = cone.intersect(&Ray::new(Point3::new(0.2, -5.0, 0.0), Vector3::new(0.0, 1.0, 0.0))).unwrap();
+        assert!((base.distance - 4.0).abs() < 1e-9 && base.front_face);
+        assert!((base.geometric_normal - Vector3::new(0.0, -1.0, 0.0)).magnitude() < 1e-9);
+    }
+}
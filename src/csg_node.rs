@@ -0,0 +1,199 @@
+use crate::{Node, Ray, HitRecord, Aabb, Shape};
+use crate::compiled_scene::{Primitive, SceneCompiler};
+use crate::node::NodeCore;
+use std::sync::Arc;
+use cgmath::{Matrix4, SquareMatrix};
+
+#[derive(Clone, Copy)]
+pub enum CsgOperation {
+    /// Inside any child.
+    Union,
+    /// Inside every child.
+    Intersection,
+    /// Inside the first child but outside all others.
+    Difference,
+}
+
+/// Combines its children, which should be closed solids, into a single solid.
+///
+/// The children are only intersected through this node, they are not rendered on their own. A child with
+/// children of its own counts as the union of its whole subtree.
+pub struct CsgNode {
+    pub core: NodeCore,
+    pub operation: CsgOperation,
+}
+
+impl CsgNode {
+    pub fn new(name: String, transform: Matrix4<f64>, operation: CsgOperation) -> CsgNode {
+        CsgNode {
+            core: NodeCore::new(name, transform),
+            operation: operation,
+        }
+    }
+
+}
+
+/// The children of a `CsgNode` placed in its local space, combined into one solid.
+pub struct CsgShape {
+    pub operation: CsgOperation,
+    pub children: Vec<Primitive>,
+}
+
+impl CsgShape {
+    /// Whether a point inside the children flagged in `inside` is inside the combined solid.
+    fn contains(&self, inside: &[bool]) -> bool {
+        match self.operation {
+            CsgOperation::Union => inside.iter().any(|x| *x),
+            CsgOperation::Intersection => !inside.is_empty() && inside.iter().all(|x| *x),
+            CsgOperation::Difference => !inside.is_empty() && inside[0] && !inside[1..].iter().any(|x| *x),
+        }
+    }
+}
+
+impl Shape for CsgShape {
+    /// Walks the crossings of all children in order and keeps those where the ray enters or leaves the
+    /// combined solid.
+    fn intersect_all(&self, ray: &Ray) -> Vec<HitRecord> {
+        // Children are traced to infinity, a crossing beyond t_max can still tell whether the ray starts inside
+        let unbounded = Ray {
+            origin: ray.origin,
+            direction: ray.direction,
+            t_min: ray.t_min,
+            t_max: f64::INFINITY,
+            time: ray.time,
+        };
+
+        let mut inside = vec![false; self.children.len()];
+        let mut crossings: Vec<(usize, HitRecord)> = vec![];
+        for (index, child) in self.children.iter().enumerate() {
+            let hits = child.intersect_all(&unbounded);
+            // Leaving a child through its back first means the ray started inside it
+            inside[index] = hits.first().is_some_and(|hit| !hit.front_face);
+            crossings.extend(hits.into_iter().map(|hit| (index, hit)));
+        }
+        crossings.sort_by(|a, b| a.1.distance.total_cmp(&b.1.distance));
+
+        let mut hits = vec![];
+        let mut was_inside = self.contains(&inside);
+        for (index, mut hit) in crossings {
+            inside[index] = !inside[index];
+            let is_inside = self.contains(&inside);
+            if is_inside != was_inside {
+                // Entering the solid is a hit from outside, this flips the faces of subtracted children
+                hit.front_face = is_inside;
+                if ray.contains(hit.distance) {
+                    hits.push(hit);
+                }
+            }
+            was_inside = is_inside;
+        }
+        hits
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        // A difference never extends past its first child
+        let count = match self.operation {
+            CsgOperation::Difference => 1,
+            _ => self.children.len(),
+        };
+        let mut bounds = Aabb::empty();
+        for child in self.children.iter().take(count) {
+            bounds = bounds.union(&child.bounds()?);
+        }
+        Some(bounds)
+    }
+}
+
+impl Node for CsgNode {
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
+
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
+    }
+
+    fn get_shape(&self, compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        let mut children = vec![];
+        for child in self.core.children() {
+            let mut primitives = vec![];
+            compiler.compile_local_subtree(child, &mut primitives);
+            // Overlapping parts of one operand must not toggle whether the ray is inside it
+            let operand = if primitives.len() > 1 {
+                let union = CsgShape { operation: CsgOperation::Union, children: primitives };
+                Some(Primitive::new(Arc::new(union), Matrix4::identity(), None, &value!(child).get_name()))
+            } else {
+                primitives.pop()
+            };
+            children.extend(operand);
+        }
+        return Some(Arc::new(CsgShape { operation: self.operation, children: children }));
+    }
+
+    fn shape_includes_children(&self) -> bool {
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Scene, Node3D, SphereNode, Color};
+    use crate::sphere_node::Sphere;
+    use crate::compiled_scene::CompiledScene;
+    use crate::node::add_child;
+    use cgmath::{Point3, Vector3};
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    /// Unit spheres centered at x = -0.5 and x = 0.5.
+    fn overlapping_spheres(operation: CsgOperation) -> CsgShape {
+        let sphere = |x: f64| Primitive::new(Arc::new(Sphere { radius: 1.0 }),
+                                             Matrix4::from_translation(Vector3::new(x, 0.0, 0.0)), None, "Sphere");
+        CsgShape { operation: operation, children: vec![sphere(-0.5), sphere(0.5)] }
+    }
+
+    fn crossings(shape: &CsgShape, origin: Point3<f64>) -> Vec<(f64, bool)> {
+        shape.intersect_all(&Ray::new(origin, Vector3::new(1.0, 0.0, 0.0))).iter()
+            .map(|hit| ((hit.distance * 1e9).round() / 1e9, hit.front_face))
+            .collect()
+    }
+
+    #[test]
+    fn crossings_are_classified_by_operation() {
+        let origin = Point3::new(-5.0, 0.0, 0.0);
+        assert_eq!(crossings(&overlapping_spheres(CsgOperation::Union), origin), vec![(3.5, true), (6.5, false)]);
+        assert_eq!(crossings(&overlapping_spheres(CsgOperation::Intersection), origin), vec![(4.5, true), (5.5, false)]);
+        // The second sphere's entry becomes the exit of the difference
+        assert_eq!(crossings(&overlapping_spheres(CsgOperation::Difference), origin), vec![(3.5, true), (4.5, false)]);
+    }
+
+    #[test]
+    fn ray_starting_inside_only_leaves() {
+        let origin = Point3::new(0.0, 0.0, 0.0);
+        assert_eq!(crossings(&overlapping_spheres(CsgOperation::Union), origin), vec![(1.5, false)]);
+        assert_eq!(crossings(&overlapping_spheres(CsgOperation::Difference), origin), vec![]);
+        assert_eq!(crossings(&overlapping_spheres(CsgOperation::Intersection), origin), vec![(0.5, false)]);
+    }
+
+    #[test]
+    fn nested_operands_are_part_of_the_solid() {
+        let root: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new("root".to_string(), Matrix4::identity())));
+        let csg: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(CsgNode::new("Csg".to_string(), Matrix4::identity(),
+                                                                            CsgOperation::Difference)));
+        let white = Color::new(1.0, 1.0, 1.0);
+        add_child(&csg, Rc::new(RefCell::new(SphereNode::new("Body".to_string(), Matrix4::identity(), 1.0, white.copy()))));
+        // The subtracted sphere sits below a transform node
+        let offset: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new(
+            "Offset".to_string(), Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0)))));
+        add_child(&offset, Rc::new(RefCell::new(SphereNode::new("Bite".to_string(), Matrix4::identity(), 0.5, white))));
+        add_child(&csg, offset);
+        add_child(&root, csg);
+
+        let compiled = CompiledScene::new(&Scene::new("scene".to_string(), root)).unwrap();
+        let ray = Ray::new(Point3::new(5.0, 0.0, 0.0), Vector3::new(-1.0, 0.0, 0.0));
+        let (hit, _) = compiled.intersect(&ray).unwrap();
+        assert!((hit.distance - 4.5).abs() < 1e-9, "hit at {}", hit.distance);
+        assert!(hit.front_face);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{Node, Color, Ray, Material, HitRecord, Aabb, Shape};
+use crate::compiled_scene::SceneCompiler;
+use crate::node::NodeCore;
+use crate::polynomial::solve_quadratic;
+use std::sync::Arc;
+use cgmath::{Vector2, Vector3, Matrix4};
+use std::f64::consts::PI;
+
+/// Capped cylinder around the local y axis, centered on the local origin.
+#[derive(Clone)]
+pub struct Cylinder {
+    pub radius: f64,
+    pub height: f64,
+}
+
+/// Scene node placing a `Cylinder`.
+pub struct CylinderNode {
+    pub core: NodeCore,
+    pub shape: Arc<Cylinder>,
+    pub material: Arc<Material>,
+}
+
+impl CylinderNode {
+    pub fn new(name: String, transform: Matrix4<f64>, radius: f64, height: f64, color: Color) -> CylinderNode {
+        CylinderNode {
+            core: NodeCore::new(name, transform),
+            shape: Arc::new(Cylinder {
+                radius: radius,
+                height: height,
+            }),
+            material: Arc::new(Material::from_color(color)),
+        }
+    }
+}
+
+/// Cylindrical mapping of a point on a surface of revolution around y that spans `[-half, half]`,
+/// u runs around the axis in the same sense as on `SphereNode` and v from the bottom to the top.
+/// Returns the UV and the derivative of the position along u.
+pub fn side_frame(x: f64, y: f64, z: f64, half: f64) -> (Vector2<f64>, Vector3<f64>) {
+    let u = (-z).atan2(x).rem_euclid(2.0 * PI) / (2.0 * PI);
+    let v = (y + half) / (2.0 * half);
+    (Vector2::new(u, v), Vector3::new(z, 0.0, -x) * (2.0 * PI))
+}
+
+/// Planar mapping of a point on a disk cap of `radius`, upright when the cap is seen from outside.
+pub fn cap_frame(x: f64, z: f64, radius: f64, top: bool) -> (Vector2<f64>, Vector3<f64>, Vector3<f64>) {
+    let size = 2.0 * radius;
+    let v_axis = if top { -1.0 } else { 1.0 };
+    (Vector2::new(x / size + 0.5, v_axis * z / size + 0.5), Vector3::new(size, 0.0, 0.0), Vector3::new(0.0, 0.0, v_axis * size))
+}
+
+impl Shape for Cylinder {
+    fn intersect_all(&self, ray: &Ray) -> Vec<HitRecord> {
+        let (o, d) = (ray.origin, ray.direction);
+        let half = self.height * 0.5;
+        let mut hits = vec![];
+
+        let a = d.x * d.x + d.z * d.z;
+        if a > 0.0 {
+            if let Some((t0, t1)) = solve_quadratic(a, 2.0 * (o.x * d.x + o.z * d.z), o.x * o.x + o.z * o.z - self.radius * self.radius) {
+                for t in [t0, t1].iter() {
+                    let mut p = o + d * *t;
+                    if ray.contains(*t) && p.y.abs() <= half {
+                        // Reproject onto the surface, the solved distance is less accurate than the error bound assumes
+                        let scale = self.radius / (p.x * p.x + p.z * p.z).sqrt();
+                        p.x *= scale;
+                        p.z *= scale;
+                        let (uv, dpdu) = side_frame(p.x, p.y, p.z, half);
+                        let dpdv = Vector3::new(0.0, self.height, 0.0);
+                        let mut hit = HitRecord::new(ray, *t, Vector3::new(p.x, 0.0, p.z), uv, dpdu, dpdv, None);
+                        hit.position = p;
+                        hits.push(hit);
+                    }
+                }
+            }
+        }
+
+        for y in [-half, half].iter() {
+            let t = (*y - o.y) / d.y;
+            let p = o + d * t;
+            if ray.contains(t) && p.x * p.x + p.z * p.z <= self.radius * self.radius {
+                let (uv, dpdu, dpdv) = cap_frame(p.x, p.z, self.radius, *y > 0.0);
+                let mut hit = HitRecord::new(ray, t, Vector3::new(0.0, y.signum(), 0.0), uv, dpdu, dpdv, None);
+                hit.position.y = *y;
+                hits.push(hit);
+            }
+        }
+
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let half_extents = Vector3::new(self.radius, self.height * 0.5, self.radius);
+        Some(Aabb::centered(half_extents))
+    }
+}
+
+impl Node for CylinderNode {
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
+
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
+    }
+
+    fn get_shape(&self, _compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        return Some(self.shape.clone());
+    }
+
+    fn get_material(&self) -> Option<Arc<Material>> {
+        return Some(Arc::clone(&self.material));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Point3, InnerSpace};
+
+    #[test]
+    fn axis_aligned_rays_hit_side_and_cap() {
+        let cylinder = Cylinder { radius: 1.0, height: 2.0 };
+        let side = cylinder.intersect_all(&Ray::new(Point3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0)));
+        assert_eq!(side.len(), 2);
+        assert!((side[0].distance - 4.0).abs() < 1e-9 && (side[1].distance - 6.0).abs() < 1e-9);
+        assert!((side[0].geometric_normal - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+
+        let cap = cylinder.intersect(&Ray::new(Point3::new(0.3, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0))).unwrap();
+        assert!((cap.distance - 4.0).abs() < 1e-9 && cap.front_face);
+        assert!((cap.geometric_normal - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-9);
+    }
+}
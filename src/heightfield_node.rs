@@ -0,0 +1,387 @@
+use crate::{Node, Color, Ray, Material, HitRecord, Aabb, Shape};
+use crate::compiled_scene::SceneCompiler;
+use crate::node::NodeCore;
+use crate::hit_record::{abs, gamma};
+use std::sync::Arc;
+use cgmath::{InnerSpace, Vector2, Vector3, Point3, Matrix4, EuclideanSpace};
+use image::{DynamicImage, GenericImage};
+use std::fs;
+use std::io;
+
+/// Lowest and highest height inside each block of cells, every level halves the resolution of the one below.
+struct MipLevel {
+    width: usize,
+    depth: usize,
+    ranges: Vec<(f64, f64)>,
+}
+
+/// Terrain over a regular grid of height samples, centered on the local origin in the xz plane.
+///
+/// Each grid cell is split into two triangles with interpolated vertex normals. Rays descend a min/max
+/// mipmap over the cells front to back, so only the cells along the ray are ever tested.
+pub struct Heightfield {
+    /// Number of samples along x and z.
+    width: usize,
+    depth: usize,
+    /// Local heights row by row, the first row lies at -z.
+    heights: Vec<f64>,
+    normals: Vec<Vector3<f64>>,
+    /// Extent of the terrain along x and z.
+    extent: Vector2<f64>,
+    levels: Vec<MipLevel>,
+}
+
+/// Scene node placing a `Heightfield`.
+pub struct HeightfieldNode {
+    pub core: NodeCore,
+    pub shape: Arc<Heightfield>,
+    pub material: Arc<Material>,
+}
+
+impl HeightfieldNode {
+    /// `heights` holds `width * depth` samples row by row, a sample of 1 is `size.y` high. `size.x` and `size.z`
+    /// are the extent of the terrain.
+    pub fn new(name: String, transform: Matrix4<f64>, width: usize, depth: usize, heights: Vec<f64>,
+               size: Vector3<f64>, color: Color) -> HeightfieldNode {
+        HeightfieldNode {
+            core: NodeCore::new(name, transform),
+            shape: Arc::new(Heightfield::new(width, depth, heights, size)),
+            material: Arc::new(Material::from_color(color)),
+        }
+    }
+
+    /// Heights from the luminance of an image, white is `size.y` high.
+    pub fn from_image(name: String, transform: Matrix4<f64>, image: &DynamicImage, size: Vector3<f64>, color: Color) -> HeightfieldNode {
+        let luma = image.to_luma();
+        let (width, depth) = luma.dimensions();
+        let heights = luma.pixels().map(|p| p.data[0] as f64 / 255.0).collect();
+        HeightfieldNode::new(name, transform, width as usize, depth as usize, heights, size, color)
+    }
+
+    /// Loads a grayscale image, binary PGM files keep their full 16 bit precision.
+    ///
+    /// Images smaller than 2x2 pixels are reported as `InvalidData`.
+    pub fn open(name: String, transform: Matrix4<f64>, path: &str, size: Vector3<f64>, color: Color) -> io::Result<HeightfieldNode> {
+        let check_size = |width: usize, depth: usize| if width < 2 || depth < 2 {
+            Err(io::Error::new(io::ErrorKind::InvalidData, format!("heightfield needs at least 2x2 samples, {} has {}x{}", path, width, depth)))
+        } else {
+            Ok(())
+        };
+        if path.to_lowercase().ends_with(".pgm") {
+            let (width, depth, heights) = read_pgm(&fs::read(path)?)?;
+            check_size(width, depth)?;
+            return Ok(HeightfieldNode::new(name, transform, width, depth, heights, size, color));
+        }
+        let image = image::open(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let (width, depth) = image.dimensions();
+        check_size(width as usize, depth as usize)?;
+        Ok(HeightfieldNode::from_image(name, transform, &image, size, color))
+    }
+
+}
+
+impl Heightfield {
+    /// See `HeightfieldNode::new`.
+    pub fn new(width: usize, depth: usize, heights: Vec<f64>, size: Vector3<f64>) -> Heightfield {
+        assert!(width >= 2 && depth >= 2 && heights.len() == width * depth, "heightfield needs at least 2x2 samples");
+        let heights: Vec<f64> = heights.iter().map(|h| h * size.y).collect();
+        let mut heightfield = Heightfield {
+            width: width,
+            depth: depth,
+            heights: heights,
+            normals: vec![],
+            extent: Vector2::new(size.x, size.z),
+            levels: vec![],
+        };
+        heightfield.normals = heightfield.vertex_normals();
+        heightfield.levels = heightfield.build_levels();
+        heightfield
+    }
+
+    /// Local position of the sample at column `i` and row `j`.
+    pub fn point(&self, i: usize, j: usize) -> Point3<f64> {
+        let x = (i as f64 / (self.width - 1) as f64 - 0.5) * self.extent.x;
+        let z = (j as f64 / (self.depth - 1) as f64 - 0.5) * self.extent.y;
+        Point3::new(x, self.heights[j * self.width + i], z)
+    }
+
+    fn vertex_normals(&self) -> Vec<Vector3<f64>> {
+        let (dx, dz) = (self.extent.x / (self.width - 1) as f64, self.extent.y / (self.depth - 1) as f64);
+        let height = |i: usize, j: usize| self.heights[j * self.width + i];
+        let mut normals = Vec::with_capacity(self.heights.len());
+        for j in 0..self.depth {
+            for i in 0..self.width {
+                // Central differences, one sided at the border
+                let (i0, i1) = (i.saturating_sub(1), (i + 1).min(self.width - 1));
+                let (j0, j1) = (j.saturating_sub(1), (j + 1).min(self.depth - 1));
+                let slope_x = (height(i1, j) - height(i0, j)) / ((i1 - i0) as f64 * dx);
+                let slope_z = (height(i, j1) - height(i, j0)) / ((j1 - j0) as f64 * dz);
+                normals.push(Vector3::new(-slope_x, 1.0, -slope_z).normalize());
+            }
+        }
+        normals
+    }
+
+    fn build_levels(&self) -> Vec<MipLevel> {
+        let (width, depth) = (self.width - 1, self.depth - 1);
+        let mut ranges = Vec::with_capacity(width * depth);
+        for j in 0..depth {
+            for i in 0..width {
+                let corners = [self.point(i, j).y, self.point(i + 1, j).y, self.point(i, j + 1).y, self.point(i + 1, j + 1).y];
+                ranges.push((corners.iter().cloned().fold(f64::INFINITY, f64::min), corners.iter().cloned().fold(f64::NEG_INFINITY, f64::max)));
+            }
+        }
+
+        let mut levels = vec![MipLevel { width: width, depth: depth, ranges: ranges }];
+        while levels.last().is_some_and(|level| level.width > 1 || level.depth > 1) {
+            let below = levels.last().unwrap();
+            let (width, depth) = (below.width.div_ceil(2), below.depth.div_ceil(2));
+            let mut ranges = vec![(f64::INFINITY, f64::NEG_INFINITY); width * depth];
+            for j in 0..below.depth {
+                for i in 0..below.width {
+                    let (lo, hi) = below.ranges[j * below.width + i];
+                    let range = &mut ranges[(j / 2) * width + i / 2];
+                    *range = (range.0.min(lo), range.1.max(hi));
+                }
+            }
+            levels.push(MipLevel { width: width, depth: depth, ranges: ranges });
+        }
+        levels
+    }
+
+    /// Local bounds of block `(i, j)` on mip level `level`.
+    fn block_bounds(&self, level: usize, i: usize, j: usize) -> Aabb {
+        let cells = 1 << level;
+        let (lo, hi) = self.levels[level].ranges[j * self.levels[level].width + i];
+        let min = self.point(i * cells, j * cells);
+        let max = self.point(((i + 1) * cells).min(self.width - 1), ((j + 1) * cells).min(self.depth - 1));
+        // Padded so rounding in the slab test can not open cracks between neighbouring blocks
+        let pad = 1e-9 * (self.extent.x.max(self.extent.y) + hi.abs().max(lo.abs()));
+        Aabb::new(Point3::new(min.x - pad, lo - pad, min.z - pad), Point3::new(max.x + pad, hi + pad, max.z + pad))
+    }
+
+    /// Collects the hits inside block `(i, j)` on `level`, children are visited front to back.
+    fn traverse(&self, ray: &Ray, level: usize, i: usize, j: usize, first_only: bool, hits: &mut Vec<HitRecord>) {
+        if level == 0 {
+            for triangle in self.cell_triangles(i, j).iter() {
+                if let Some(hit) = self.intersect_triangle(ray, triangle) {
+                    if !first_only {
+                        hits.push(hit);
+                    } else if hits.first().is_none_or(|nearest| hit.distance < nearest.distance) {
+                        hits.clear();
+                        hits.push(hit);
+                    }
+                }
+            }
+            return;
+        }
+
+        let below = &self.levels[level - 1];
+        let mut children = vec![];
+        for cj in (2 * j)..(2 * j + 2).min(below.depth) {
+            for ci in (2 * i)..(2 * i + 2).min(below.width) {
+                if let Some((t0, _)) = self.block_bounds(level - 1, ci, cj).intersect(ray) {
+                    children.push((t0, ci, cj));
+                }
+            }
+        }
+        children.sort_by(|a, b| a.0.total_cmp(&b.0));
+        for (t0, ci, cj) in children {
+            // Nothing in a block that starts behind the nearest hit can be closer
+            if first_only && hits.first().is_some_and(|nearest| nearest.distance < t0) {
+                return;
+            }
+            self.traverse(ray, level - 1, ci, cj, first_only, hits);
+        }
+    }
+
+    fn cell_triangles(&self, i: usize, j: usize) -> [[(usize, usize); 3]; 2] {
+        [[(i, j), (i + 1, j), (i + 1, j + 1)], [(i, j), (i + 1, j + 1), (i, j + 1)]]
+    }
+
+    /// Möller-Trumbore test against one triangle of grid samples.
+    fn intersect_triangle(&self, ray: &Ray, triangle: &[(usize, usize); 3]) -> Option<HitRecord> {
+        let p0 = self.point(triangle[0].0, triangle[0].1);
+        let e1 = self.point(triangle[1].0, triangle[1].1) - p0;
+        let e2 = self.point(triangle[2].0, triangle[2].1) - p0;
+
+        let p = ray.direction.cross(e2);
+        let det = e1.dot(p);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let s = ray.origin - p0;
+        let b1 = s.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let q = s.cross(e1);
+        let b2 = ray.direction.dot(q) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let distance = e2.dot(q) * inv_det;
+        if !ray.contains(distance) {
+            return None;
+        }
+
+        // The terrain faces up, u runs along x and v away from the first row like an image of the heights
+        let normal = e1.cross(e2);
+        let normal = if normal.y < 0.0 { -normal } else { normal };
+        let position = ray.origin + ray.direction * distance;
+        let uv = Vector2::new(position.x / self.extent.x + 0.5, 0.5 - position.z / self.extent.y);
+        let (slope_x, slope_z) = (-normal.x / normal.y, -normal.z / normal.y);
+        let dpdu = Vector3::new(self.extent.x, slope_x * self.extent.x, 0.0);
+        let dpdv = Vector3::new(0.0, -slope_z * self.extent.y, -self.extent.y);
+
+        let mut hit = HitRecord::new(ray, distance, normal, uv, dpdu, dpdv, None);
+        let index = |v: (usize, usize)| v.1 * self.width + v.0;
+        let smooth = self.normals[index(triangle[0])] * (1.0 - b1 - b2) + self.normals[index(triangle[1])] * b1
+            + self.normals[index(triangle[2])] * b2;
+        let smooth = smooth.normalize();
+        hit.shading_normal = if smooth.dot(hit.geometric_normal) < 0.0 { -smooth } else { smooth };
+        // Evaluate the position from the barycentrics, its error only depends on the sample magnitudes
+        let (b0, p1, p2) = (1.0 - b1 - b2, p0 + e1, p0 + e2);
+        hit.position = Point3::from_vec(p0.to_vec() * b0 + p1.to_vec() * b1 + p2.to_vec() * b2);
+        hit.error = (abs(p0.to_vec() * b0) + abs(p1.to_vec() * b1) + abs(p2.to_vec() * b2)) * gamma(7);
+        hit.primitive_index = 2 * (triangle[0].1 * (self.width - 1) + triangle[0].0) + if triangle[2].0 == triangle[0].0 { 1 } else { 0 };
+        Some(hit)
+    }
+
+    /// All surface crossings of a local ray within its extent ordered by distance, or only the nearest one.
+    fn local_hits(&self, ray: &Ray, first_only: bool) -> Vec<HitRecord> {
+        let top = self.levels.len() - 1;
+        let mut hits = vec![];
+        if self.block_bounds(top, 0, 0).intersect(ray).is_some() {
+            self.traverse(ray, top, 0, 0, first_only, &mut hits);
+        }
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits
+    }
+}
+
+/// Parses a binary PGM (P5) image into its width, height and samples normalized to `[0, 1]`.
+fn read_pgm(data: &[u8]) -> io::Result<(usize, usize, Vec<f64>)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    // Header: magic, width, height and maximum value separated by whitespace, comments start with '#'
+    let mut fields = vec![];
+    let mut position = 0;
+    while fields.len() < 4 {
+        while position < data.len() && (data[position].is_ascii_whitespace() || data[position] == b'#') {
+            if data[position] == b'#' {
+                while position < data.len() && data[position] != b'\n' {
+                    position += 1;
+                }
+            } else {
+                position += 1;
+            }
+        }
+        let start = position;
+        while position < data.len() && !data[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        if start == position {
+            return Err(invalid("truncated PGM header"));
+        }
+        fields.push(String::from_utf8_lossy(&data[start..position]).into_owned());
+    }
+    if fields[0] != "P5" {
+        return Err(invalid("only binary PGM (P5) files are supported"));
+    }
+    let number = |field: &str| field.parse::<usize>().map_err(|_| invalid("malformed PGM header"));
+    let (width, height, max) = (number(&fields[1])?, number(&fields[2])?, number(&fields[3])?);
+    if max == 0 || max > 65535 {
+        return Err(invalid("PGM maximum value out of range"));
+    }
+
+    // A single whitespace character separates the header from the samples
+    let pixels = &data[(position + 1).min(data.len())..];
+    let bytes = if max < 256 { 1 } else { 2 };
+    let count = width.checked_mul(height).filter(|count| count.checked_mul(bytes).is_some())
+        .ok_or_else(|| invalid("PGM size out of range"))?;
+    if pixels.len() < count * bytes {
+        return Err(invalid("truncated PGM data"));
+    }
+    let samples = (0..count).map(|i| {
+        let value = if bytes == 1 { pixels[i] as usize } else { (pixels[2 * i] as usize) << 8 | pixels[2 * i + 1] as usize };
+        value as f64 / max as f64
+    }).collect();
+    Ok((width, height, samples))
+}
+
+impl Shape for Heightfield {
+    fn intersect_all(&self, ray: &Ray) -> Vec<HitRecord> {
+        self.local_hits(ray, false)
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        self.local_hits(ray, true).into_iter().next()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.block_bounds(self.levels.len() - 1, 0, 0))
+    }
+}
+
+impl Node for HeightfieldNode {
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
+
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
+    }
+
+    fn get_shape(&self, _compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        return Some(self.shape.clone());
+    }
+
+    fn get_material(&self) -> Option<Arc<Material>> {
+        return Some(Arc::clone(&self.material));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_8_bit_pgm() {
+        let mut data = b"P5\n# terrain\n3 2\n255\n".to_vec();
+        data.extend_from_slice(&[0, 51, 255, 102, 204, 255]);
+        let (width, height, samples) = read_pgm(&data).unwrap();
+        assert_eq!((width, height), (3, 2));
+        assert_eq!(samples, vec![0.0, 0.2, 1.0, 0.4, 0.8, 1.0]);
+    }
+
+    #[test]
+    fn reads_16_bit_pgm_big_endian() {
+        let mut data = b"P5 2 2 65535\n".to_vec();
+        data.extend_from_slice(&[0x00, 0x00, 0xff, 0xff, 0x80, 0x00, 0x00, 0x01]);
+        let (width, height, samples) = read_pgm(&data).unwrap();
+        assert_eq!((width, height), (2, 2));
+        assert_eq!(samples, vec![0.0, 1.0, 32768.0 / 65535.0, 1.0 / 65535.0]);
+    }
+
+    #[test]
+    fn rejects_malformed_pgm() {
+        let invalid = |data: &[u8]| read_pgm(data).unwrap_err().kind() == io::ErrorKind::InvalidData;
+        assert!(invalid(b"P2 2 2 255\n0 0 0 0"));
+        assert!(invalid(b"P5 2 2\n"));
+        assert!(invalid(b"P5 2 2 255\n\x00\x00\x00"));
+        assert!(invalid(b"P5 2 2 70000\n\x00\x00\x00\x00\x00\x00\x00\x00"));
+        assert!(invalid(b"P5 99999999999 99999999999 255\n"));
+    }
+
+    #[test]
+    fn open_rejects_images_below_2x2() {
+        let path = std::env::temp_dir().join(format!("heightfield-{}.pgm", std::process::id()));
+        fs::write(&path, b"P5 1 4 255\n\x00\x40\x80\xff").unwrap();
+        let result = HeightfieldNode::open("Terrain".to_string(), Matrix4::from_scale(1.0), path.to_str().unwrap(),
+                                           Vector3::new(1.0, 1.0, 1.0), Color::new(1.0, 1.0, 1.0));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{Ray, Material};
+use cgmath::{InnerSpace, Vector2, Vector3, Point3, Matrix, Matrix3, Matrix4, EuclideanSpace, Transform};
+use std::sync::Arc;
+
+/// Everything known about a ray-surface intersection.
+pub struct HitRecord {
+    pub distance: f64,
+    pub position: Point3<f64>,
+    /// Conservative bound on the absolute floating point error of `position` per axis.
+    pub error: Vector3<f64>,
+    /// Normal of the actual geometry, always facing the side the ray came from.
+    pub geometric_normal: Vector3<f64>,
+    /// Normal used for lighting, starts out as the geometric normal and is perturbed by normal and bump maps.
+    pub shading_normal: Vector3<f64>,
+    /// True if the ray hit the outside of the surface.
+    pub front_face: bool,
+    pub uv: Vector2<f64>,
+    /// World space derivatives of the position along u and v, they span the tangent frame.
+    pub dpdu: Vector3<f64>,
+    pub dpdv: Vector3<f64>,
+    /// Index of the hit triangle for meshes, 0 for single primitives.
+    pub primitive_index: usize,
+    pub material: Option<Arc<Material>>,
+}
+
+impl HitRecord {
+    /// Orients the `outward_normal` against the ray and records which side was hit.
+    pub fn new(ray: &Ray, distance: f64, outward_normal: Vector3<f64>, uv: Vector2<f64>,
+               dpdu: Vector3<f64>, dpdv: Vector3<f64>, material: Option<Arc<Material>>) -> HitRecord {
+        let outward_normal = outward_normal.normalize();
+        let front_face = ray.direction.dot(outward_normal) < 0.0;
+        let normal = if front_face { outward_normal } else { -outward_normal };
+        let offset = ray.direction * distance;
+        HitRecord {
+            distance: distance,
+            position: ray.origin + offset,
+            // Bound for a point evaluated as `o + t * d`, shapes with a better estimate overwrite it
+            error: (abs(ray.origin.to_vec()) + abs(offset)) * gamma(7),
+            geometric_normal: normal,
+            shading_normal: normal,
+            front_face: front_face,
+            uv: uv,
+            dpdu: dpdu,
+            dpdv: dpdv,
+            primitive_index: 0,
+            material: material,
+        }
+    }
+
+    /// Moves a hit found in object space into world space, `inverse` is the inverse of `world`.
+    ///
+    /// Normals are transformed by the inverse transpose so they stay perpendicular under non-uniform scale.
+    pub fn to_world(mut self, world: &Matrix4<f64>, inverse: &Matrix4<f64>) -> HitRecord {
+        let normal_matrix = Matrix3::from_cols(inverse.x.truncate(), inverse.y.truncate(), inverse.z.truncate()).transpose();
+        let abs_world = Matrix3::from_cols(abs(world.x.truncate()), abs(world.y.truncate()), abs(world.z.truncate()));
+        let p = self.position.to_vec();
+        // Error of the local position carried through the matrix plus the rounding of the transform itself
+        self.error = abs_world * self.error * (1.0 + gamma(3)) + (abs_world * abs(p) + abs(world.w.truncate())) * gamma(3);
+        self.position = world.transform_point(self.position);
+        self.geometric_normal = (normal_matrix * self.geometric_normal).normalize();
+        self.shading_normal = (normal_matrix * self.shading_normal).normalize();
+        self.dpdu = world.transform_vector(self.dpdu);
+        self.dpdv = world.transform_vector(self.dpdv);
+        self
+    }
+
+    /// Origin for a ray leaving the surface in `direction`.
+    ///
+    /// The position is pushed along the geometric normal just past its error bounds (see PBRT, 3.9), so
+    /// the new ray can not hit the surface it starts on, independent of the scale of the scene.
+    pub fn offset_origin(&self, direction: Vector3<f64>) -> Point3<f64> {
+        let normal = self.geometric_normal;
+        let d = abs(normal).dot(self.error);
+        let mut offset = normal * d;
+        if direction.dot(normal) < 0.0 {
+            offset = -offset;
+        }
+        let p = self.position + offset;
+        // Round away from the surface so the offset survives the addition
+        let away = |x: f64, o: f64| if o > 0.0 { x.next_up() } else if o < 0.0 { x.next_down() } else { x };
+        Point3::new(away(p.x, offset.x), away(p.y, offset.y), away(p.z, offset.z))
+    }
+
+    /// Secondary ray leaving the surface in `direction`, unbounded.
+    pub fn spawn_ray(&self, direction: Vector3<f64>) -> Ray {
+        Ray::new(self.offset_origin(direction), direction)
+    }
+
+    /// Tangent frame `(tangent, bitangent, normal)` around the shading normal, the tangent follows `dpdu`.
+    pub fn tangent_frame(&self) -> (Vector3<f64>, Vector3<f64>, Vector3<f64>) {
+        let normal = self.shading_normal;
+        let tangent = self.dpdu - normal * normal.dot(self.dpdu);
+        let tangent = if tangent.magnitude2() > 1e-24 {
+            tangent.normalize()
+        } else if normal.x.abs() > 0.9 {
+            normal.cross(Vector3::new(0.0, 1.0, 0.0)).normalize()
+        } else {
+            normal.cross(Vector3::new(1.0, 0.0, 0.0)).normalize()
+        };
+        (tangent, normal.cross(tangent), normal)
+    }
+}
+
+/// Bound on the relative error of `n` successive floating point operations, `n * eps / (1 - n * eps)`.
+pub fn gamma(n: i32) -> f64 {
+    let e = f64::EPSILON * 0.5 * n as f64;
+    e / (1.0 - e)
+}
+
+pub fn abs(v: Vector3<f64>) -> Vector3<f64> {
+    Vector3::new(v.x.abs(), v.y.abs(), v.z.abs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiled_scene::Primitive;
+    use crate::Shape;
+    use crate::sphere_node::Sphere;
+    use crate::plane::PlaneShape;
+    use cgmath::Deg;
+
+    #[test]
+    fn normal_faces_the_ray() {
+        let outward = Vector3::new(0.0, 0.0, 2.0);
+        let (uv, dpdu, dpdv) = (Vector2::new(0.25, 0.5), Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0));
+        let front = HitRecord::new(&Ray::new(Point3::new(0.0, 0.0, 3.0), Vector3::new(0.0, 0.0, -1.0)), 2.0, outward,
+                                   uv, dpdu, dpdv, None);
+        assert!(front.front_face && front.geometric_normal == Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!((front.position, front.uv), (Point3::new(0.0, 0.0, 1.0), uv));
+        let back = HitRecord::new(&Ray::new(Point3::new(0.0, 0.0, -3.0), Vector3::new(0.0, 0.0, 1.0)), 3.0, outward,
+                                  uv, dpdu, dpdv, None);
+        assert!(!back.front_face && back.geometric_normal == Vector3::new(0.0, 0.0, -1.0));
+        assert_eq!(back.shading_normal, back.geometric_normal);
+    }
+
+    #[test]
+    fn to_world_keeps_normals_perpendicular() {
+        let ray = Ray::new(Point3::new(2.0, 2.0, 0.0), Vector3::new(-1.0, -1.0, 0.0));
+        let hit = HitRecord::new(&ray, 1.5, Vector3::new(1.0, 1.0, 0.0), Vector2::new(0.0, 0.0),
+                                 Vector3::new(1.0, -1.0, 0.0), Vector3::new(0.0, 0.0, 1.0), None);
+        let world = Matrix4::from_nonuniform_scale(3.0, 1.0, 1.0);
+        let hit = hit.to_world(&world, &world.inverse_transform().unwrap());
+        // The tangent stretches with the surface, the normal must stay perpendicular to it
+        assert_eq!(hit.dpdu, Vector3::new(3.0, -1.0, 0.0));
+        assert!(hit.geometric_normal.dot(hit.dpdu).abs() < 1e-12 && (hit.geometric_normal.magnitude() - 1.0).abs() < 1e-12);
+        let (tangent, bitangent, normal) = hit.tangent_frame();
+        assert!(tangent.dot(hit.dpdu) > 0.0 && tangent.dot(bitangent).abs() < 1e-12 && normal == hit.shading_normal);
+        assert!((tangent.cross(bitangent) - normal).magnitude() < 1e-12);
+    }
+
+    #[test]
+    fn offset_origin_clears_the_error_bounds() {
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        let mut hit = HitRecord::new(&ray, 1.0, Vector3::new(0.0, 1.0, 0.0), Vector2::new(0.0, 0.0),
+                                     Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0), None);
+        hit.error = Vector3::new(1e-3, 1e-3, 1e-3);
+        let above = hit.offset_origin(Vector3::new(0.3, 1.0, 0.0));
+        let below = hit.offset_origin(Vector3::new(0.3, -1.0, 0.0));
+        assert!(above.y > 1e-3 && below.y < -1e-3, "{:?} {:?}", above, below);
+        // Only the normal direction is pushed
+        assert_eq!((above.x, above.z, below.x, below.z), (0.0, 0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn error_grows_with_the_transform() {
+        let ray = Ray::new(Point3::new(0.0, 0.0, 2.0), Vector3::new(0.0, 0.0, -1.0));
+        let placed = |world: Matrix4<f64>| {
+            let hit = Sphere { radius: 1.0 }.intersect(&ray).unwrap();
+            hit.to_world(&world, &world.inverse_transform().unwrap())
+        };
+        let near = placed(Matrix4::from_scale(1.0));
+        let far = placed(Matrix4::from_translation(Vector3::new(1e6, 0.0, 0.0)));
+        // Rounding the translated position alone costs about 1e6 * eps
+        assert!(far.error.x >= 1e6 * gamma(3) && near.error.x < 1e-12, "{:?} {:?}", near.error, far.error);
+    }
+
+    #[test]
+    fn spawned_rays_do_not_hit_their_own_surface() {
+        let center = Vector3::new(1e6, -3e5, 2e5);
+        let placement = Matrix4::from_translation(center) * Matrix4::from_angle_x(Deg(31.0))
+            * Matrix4::from_angle_z(Deg(17.0)) * Matrix4::from_nonuniform_scale(2.0, 0.5, 1.0);
+        let shapes: [Arc<dyn Shape>; 2] = [Arc::new(Sphere { radius: 1.0 }),
+                                            Arc::new(PlaneShape::Rectangle { width: 2.0, height: 2.0 })];
+        for shape in shapes.iter() {
+            let primitive = Primitive::new(Arc::clone(shape), placement, None, "Shape");
+            for i in 0..64 {
+                // Rays from the upper half all around, aimed slightly off the center
+                let phi = i as f64 * 2.4;
+                let y = 1.0 - (i as f64 + 0.5) / 64.0;
+                let r = (1.0 - y * y).sqrt();
+                let from = placement.transform_vector(Vector3::new(r * phi.cos(), y, r * phi.sin()));
+                let aim = placement.transform_vector(Vector3::new(0.3 * phi.sin(), 0.0, 0.3 * phi.cos()));
+                let ray = Ray::new(Point3::from_vec(center + from * 5.0), aim - from * 5.0);
+                let hit = primitive.intersect(&ray).unwrap();
+
+                let normal = hit.geometric_normal;
+                assert!(primitive.intersect(&hit.spawn_ray(normal)).is_none(), "ray {} hit its own surface leaving", i);
+                // Going in, the sphere is only hit on its far side and the rectangle not at all
+                let inside = primitive.intersect(&hit.spawn_ray(-normal));
+                assert!(inside.is_none_or(|hit| hit.distance > 1e-3), "ray {} hit its own surface entering", i);
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{Node, Material, Shape};
+use crate::compiled_scene::SceneCompiler;
+use crate::node::NodeCore;
+use std::rc::Rc;
+use std::sync::Arc;
+use cgmath::Matrix4;
+use std::cell::RefCell;
+
+/// Places a shared prototype subtree in the scene, only the transform and an optional material are per instance.
+///
+/// The prototype is built like any other subtree but never added to the scene itself, its world space acts as
+/// the local space of every instance. It is compiled once and shared by all instances, so the geometry exists
+/// only once in memory.
+pub struct InstanceNode {
+    pub core: NodeCore,
+    pub prototype: Rc<RefCell<dyn Node>>,
+    /// Replaces the materials of the prototype if set.
+    pub material: Option<Arc<Material>>,
+}
+
+impl InstanceNode {
+    pub fn new(name: String, transform: Matrix4<f64>, prototype: Rc<RefCell<dyn Node>>) -> InstanceNode {
+        InstanceNode {
+            core: NodeCore::new(name, transform),
+            prototype: prototype,
+            material: None,
+        }
+    }
+}
+
+impl Node for InstanceNode {
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
+
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
+    }
+
+    fn get_shape(&self, compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        return Some(compiler.prototype(&self.prototype));
+    }
+
+    fn get_material(&self) -> Option<Arc<Material>> {
+        return self.material.as_ref().map(Arc::clone);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Scene, Node3D, SphereNode, Color, Ray};
+    use crate::compiled_scene::CompiledScene;
+    use crate::node::add_child;
+    use cgmath::{Point3, Vector3, SquareMatrix};
+
+    #[test]
+    fn instances_share_one_prototype() {
+        let prototype: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new("Prototype".to_string(), Matrix4::identity())));
+        add_child(&prototype, Rc::new(RefCell::new(SphereNode::new("Ball".to_string(), Matrix4::identity(), 1.0,
+                                                                    Color::new(1.0, 0.0, 0.0)))));
+        let blue = Arc::new(Material::from_color(Color::new(0.0, 0.0, 1.0)));
+        let left = InstanceNode::new("Left".to_string(), Matrix4::from_translation(Vector3::new(-3.0, 0.0, 0.0)),
+                                     Rc::clone(&prototype));
+        let mut right = InstanceNode::new("Right".to_string(), Matrix4::from_translation(Vector3::new(3.0, 0.0, 0.0)),
+                                          Rc::clone(&prototype));
+        right.material = Some(Arc::clone(&blue));
+        let root: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new("root".to_string(), Matrix4::identity())));
+        add_child(&root, Rc::new(RefCell::new(left)));
+        add_child(&root, Rc::new(RefCell::new(right)));
+        let compiled = CompiledScene::new(&Scene::new("scene".to_string(), root)).unwrap();
+
+        let primitives = &compiled.geometry.primitives;
+        assert_eq!(primitives.len(), 2);
+        assert!(Arc::ptr_eq(&primitives[0].shape, &primitives[1].shape));
+
+        let trace = |x: f64| compiled.intersect(&Ray::new(Point3::new(x, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0)));
+        let (hit, _) = trace(-3.0).unwrap();
+        assert!((hit.distance - 4.0).abs() < 1e-9);
+        assert_eq!(hit.material.unwrap().name, "color(255, 0, 0)");
+        let (hit, _) = trace(3.0).unwrap();
+        assert!((hit.distance - 4.0).abs() < 1e-9);
+        assert!(Arc::ptr_eq(&hit.material.unwrap(), &blue));
+        // The prototype itself is not part of the scene
+        assert!(trace(0.0).is_none());
+    }
+}
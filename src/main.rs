@@ -1,3 +1,5 @@
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+
 #[macro_use]
 pub mod utils;
 pub mod node;
@@ -9,24 +11,56 @@ pub mod scene_items;
 pub mod render_system;
 pub mod light;
 pub mod plane;
+pub mod aov;
+pub mod texture;
+pub mod procedural_texture;
+pub mod material;
+pub mod triangle_node;
+pub mod hit_record;
+pub mod bounds;
+pub mod polynomial;
+pub mod box_node;
+pub mod cylinder_node;
+pub mod cone_node;
+pub mod torus_node;
+pub mod capsule_node;
+pub mod csg_node;
+pub mod sdf_node;
+pub mod heightfield_node;
+pub mod instance_node;
+pub mod shape;
+pub mod bvh;
+pub mod compiled_scene;
+pub mod rng;
+pub mod motion;
 
 //use self::utils::Utils;
 extern crate cgmath;
 use self::plane::Plane;
 use self::scene::Scene;
-use self::camera_node::CameraNode; 
+use self::camera_node::{CameraNode, CompiledCamera};
 use self::node_3d::Node3D;
 use self::sphere_node::SphereNode;
 use self::scene_items::{Color, Ray};
-use self::node::Node; 
-use self::render_system::RenderSystem;
-use self::light::{DirectionalLight, SphericalLight, Light};
-
-use cgmath::{Point3, Vector3, Matrix4, Deg};
-use std::rc::{Weak, Rc};
+use self::node::{Node, add_child};
+use self::render_system::{RenderSystem, AlphaMode};
+use self::aov::{Aov, AovBuffer, AovSample};
+use self::texture::{Texture, TextureCoord, ConstantTexture};
+use self::material::Material;
+use self::hit_record::HitRecord;
+use self::bounds::Aabb;
+use self::light::{SphericalLight, Light, CompiledLight};
+use self::shape::{Shape, SurfaceSample};
+use self::bvh::Bvh;
+use self::rng::Rng;
+use self::motion::{Motion, MotionChain};
+
+use cgmath::{Vector3, Matrix4, Deg};
+use std::rc::Rc;
 use std::cell::RefCell;
+use std::io;
 
-fn main() {
+fn main() -> io::Result<()> {
     //-------------- Create empty Scene ---------------
     let camera = Rc::new(RefCell::new(CameraNode::new(
         "main_camera".to_string(),
@@ -36,14 +70,13 @@ fn main() {
         480
     )));
 
-    let root = Rc::new(RefCell::new(Node3D::new(
+    let root: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new(
         "root".to_string(),
         Matrix4::from_translation(Vector3::new(0.0, 0.0, 0.0)),
     )));
 
     let root_move = Rc::clone(&root);
-    let cam_move = Rc::clone(&camera);
-    let mut scene = Scene::new("main_scene".to_string(), root_move, cam_move);
+    let mut scene = Scene::new("main_scene".to_string(), root_move);
 
     //-------------- Add Sphere's to Scene ------------
 
@@ -70,14 +103,14 @@ fn main() {
 
     let r = Matrix4::from_angle_x(Deg(10.0));
     let t = Matrix4::from_translation(Vector3::new(0.0, 1.0, -1.0));    
-    let sphere_root = Rc::new(RefCell::new(Node3D::new(
+    let sphere_root: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new(
         "Sphere root".to_string(),
         t*r,
     )));    
 
-    value!(sphere_root).add_child(sphere1);
-    value!(sphere_root).add_child(sphere2);
-    value!(sphere_root).add_child(sphere3);
+    add_child(&sphere_root, sphere1);
+    add_child(&sphere_root, sphere2);
+    add_child(&sphere_root, sphere3);
     
 
     //----------- Add Planes -------------------
@@ -85,49 +118,59 @@ fn main() {
     //let s = Matrix4::from_scale(1.0);
     let r = Matrix4::from_angle_x(Deg(90.0));
     let t = Matrix4::from_translation(Vector3::new(0.0, 0.0, -10.0));
-    let plane1 = Rc::new(RefCell::new(Plane::new(
+    let plane1 = Rc::new(RefCell::new(Plane::rectangle(
         "Plane back grey".to_string(),
         t*r,
+        8.0,
+        8.0,
         Color::new_rgb(230, 230, 230)
     )));
 
     let r = Matrix4::from_angle_z(Deg(90.0));
     let t = Matrix4::from_translation(Vector3::new(4.0, 0.0, -6.0));
-    let plane2 = Rc::new(RefCell::new(Plane::new(
+    let plane2 = Rc::new(RefCell::new(Plane::rectangle(
         "Plane right green".to_string(),
         t*r,
+        8.0,
+        8.0,
         Color::new_rgb(36, 145, 44)
     )));
 
     let r = Matrix4::from_angle_z(Deg(-90.0));
     let t = Matrix4::from_translation(Vector3::new(-4.0, 0.0, -6.0));
-    let plane3 = Rc::new(RefCell::new(Plane::new(
+    let plane3 = Rc::new(RefCell::new(Plane::rectangle(
         "Plane left red".to_string(),
         t*r,
+        8.0,
+        8.0,
         Color::new_rgb(229, 57, 57)
     )));
 
     let r = Matrix4::from_angle_y(Deg(0.0));
     let t = Matrix4::from_translation(Vector3::new(0.0, -3.99, -6.0));
-    let plane4 = Rc::new(RefCell::new(Plane::new(
+    let plane4 = Rc::new(RefCell::new(Plane::rectangle(
         "Plane down grey".to_string(),
         t*r,
+        8.0,
+        8.0,
         Color::new_rgb(230, 230, 230)
     )));
 
     let r = Matrix4::from_angle_z(Deg(180.0));
     let t = Matrix4::from_translation(Vector3::new(0.0, 3.99, -6.0));
-    let plane5 = Rc::new(RefCell::new(Plane::new(
+    let plane5 = Rc::new(RefCell::new(Plane::rectangle(
         "Plane up grey".to_string(),
         t*r,
+        8.0,
+        8.0,
         Color::new_rgb(230, 230, 230)
     )));
 
-    value!(sphere_root).add_child(plane1);
-    value!(sphere_root).add_child(plane2);
-    value!(sphere_root).add_child(plane3);
-    value!(sphere_root).add_child(plane4);
-    value!(sphere_root).add_child(plane5);
+    add_child(&sphere_root, plane1);
+    add_child(&sphere_root, plane2);
+    add_child(&sphere_root, plane3);
+    add_child(&sphere_root, plane4);
+    add_child(&sphere_root, plane5);
     
     //----------- Add Lights to Scene ----------
     let light1 = Rc::new(RefCell::new(Light::Spherical(SphericalLight::new(
@@ -151,20 +194,20 @@ fn main() {
         10.0
     ))));
 
-    scene.lights.push(Rc::clone(&light1));
-    scene.lights.push(Rc::clone(&light2));
-    scene.lights.push(Rc::clone(&light3));
-    value!(sphere_root).add_child(light1);
-    value!(sphere_root).add_child(light2);
-    value!(sphere_root).add_child(light3);
-    value!(root).add_child(sphere_root);
+    add_child(&sphere_root, light1);
+    add_child(&sphere_root, light2);
+    add_child(&sphere_root, light3);
+    add_child(&root, sphere_root);
+    add_child(&root, camera);
+    scene.set_active_camera("main_camera")?;
 
     //------------ Render Scene ---------------
     
-    let renderer = RenderSystem {
-        output_path: "output.png".to_string()
-    };
+    let mut renderer = RenderSystem::new("output.png".to_string());
+    renderer.transparent_background = false;
+    renderer.alpha_mode = AlphaMode::Straight;
+    renderer.aovs = vec![Aov::Albedo, Aov::Normal, Aov::Depth];
 
     println!("Render!");
-    renderer.render(&scene, camera);
+    renderer.render(&scene)
 }   
@@ -0,0 +1,150 @@
+use crate::{Color, Texture, TextureCoord, ConstantTexture};
+use cgmath::{InnerSpace, Vector3};
+use std::sync::Arc;
+
+/// UV offset used for the finite differences of bump maps.
+const BUMP_DELTA: f64 = 1e-3;
+
+/// Surface description shared between nodes, every color parameter is driven by a texture.
+pub struct Material {
+    pub name: String,
+    pub albedo: Arc<dyn Texture>,
+    /// Tangent space normal map, RGB in `[0, 1]` encodes a normal in `[-1, 1]` with z along the surface normal.
+    pub normal_map: Option<Arc<dyn Texture>>,
+    /// Height field whose scalar value displaces the shading normal.
+    pub bump_map: Option<Arc<dyn Texture>>,
+    /// World space height of a bump map value of 1.
+    pub bump_scale: f64,
+    /// Radiance leaving the surface on its own, geometry with an emission texture lights the scene.
+    pub emission: Option<Arc<dyn Texture>>,
+    /// Emit from the back side of the surface as well.
+    pub two_sided: bool,
+    /// Points sampled on the surface per shading point when it lights the scene.
+    pub light_samples: u32,
+}
+
+impl Material {
+    pub fn new(name: String, albedo: Arc<dyn Texture>) -> Material {
+        Material {
+            name: name,
+            albedo: albedo,
+            normal_map: None,
+            bump_map: None,
+            bump_scale: 0.05,
+            emission: None,
+            two_sided: false,
+            light_samples: 4,
+        }
+    }
+
+    /// Untextured material with a single albedo, named after its color.
+    pub fn from_color(color: Color) -> Material {
+        let name = format!("color({}, {}, {})", color.red(), color.green(), color.blue());
+        Material::new(name, Arc::new(ConstantTexture::new(color)))
+    }
+
+    /// Black material emitting `color` scaled by `strength`.
+    pub fn emissive(color: Color, strength: f32) -> Material {
+        let name = format!("emissive({}, {}, {})", color.red(), color.green(), color.blue());
+        let mut material = Material::new(name, Arc::new(ConstantTexture::new(Color::new(0.0, 0.0, 0.0))));
+        material.emission = Some(Arc::new(ConstantTexture::new(color * strength)));
+        material
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emission.is_some()
+    }
+
+    /// Radiance emitted at `coord` towards the side of the surface a hit with `front_face` was seen from.
+    pub fn emitted(&self, coord: &TextureCoord, front_face: bool) -> Color {
+        match self.emission {
+            Some(ref emission) if front_face || self.two_sided => emission.sample(coord),
+            _ => Color::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Applies the normal and bump maps to the outward geometric `normal`.
+    ///
+    /// `dpdu` and `dpdv` are the world space derivatives of the surface position along the UVs,
+    /// `coord_at(du, dv)` returns the texture coordinate offset by `(du, dv)` in UV space.
+    pub fn shading_normal(&self, coord: &TextureCoord, normal: Vector3<f64>, dpdu: Vector3<f64>, dpdv: Vector3<f64>,
+                          coord_at: &dyn Fn(f64, f64) -> TextureCoord) -> Vector3<f64> {
+        let mut normal = normal;
+        let (mut dpdu, mut dpdv) = (dpdu, dpdv);
+
+        if let Some(ref bump) = self.bump_map {
+            let height = bump.sample_float(coord) as f64;
+            let du = (bump.sample_float(&coord_at(BUMP_DELTA, 0.0)) as f64 - height) / BUMP_DELTA;
+            let dv = (bump.sample_float(&coord_at(0.0, BUMP_DELTA)) as f64 - height) / BUMP_DELTA;
+            dpdu += normal * (du * self.bump_scale);
+            dpdv += normal * (dv * self.bump_scale);
+            let bumped = dpdu.cross(dpdv);
+            if bumped.magnitude2() > 0.0 {
+                let bumped = bumped.normalize();
+                normal = if bumped.dot(normal) < 0.0 { -bumped } else { bumped };
+            }
+        }
+
+        if let Some(ref map) = self.normal_map {
+            let c = map.sample(coord);
+            let local = Vector3::new(c.red as f64 * 2.0 - 1.0, c.green as f64 * 2.0 - 1.0, c.blue as f64 * 2.0 - 1.0);
+            // Orthonormal frame with the tangent along dpdu and the bitangent on the side of dpdv
+            let tangent = dpdu - normal * normal.dot(dpdu);
+            if tangent.magnitude2() > 0.0 {
+                let tangent = tangent.normalize();
+                let bitangent = normal.cross(tangent);
+                let bitangent = if bitangent.dot(dpdv) < 0.0 { -bitangent } else { bitangent };
+                let mapped = tangent * local.x + bitangent * local.y + normal * local.z;
+                if mapped.magnitude2() > 0.0 {
+                    normal = mapped.normalize();
+                }
+            }
+        }
+        normal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procedural_texture::{GradientTexture, TextureSpace, ColorRamp};
+    use cgmath::{Point3, Vector2};
+
+    /// Gray level rising from 0 to 0.5 as u goes from 0 to 1.
+    fn ramp_u() -> GradientTexture {
+        GradientTexture::new(TextureSpace::Uv, 0.5, Vector3::new(1.0, 0.0, 0.0),
+                             ColorRamp::two(Color::new(0.0, 0.0, 0.0), Color::new(1.0, 1.0, 1.0)))
+    }
+
+    fn shade_flat(material: &Material) -> Vector3<f64> {
+        let coord_at = |du: f64, dv: f64| TextureCoord {
+            uv: Vector2::new(0.5 + du, 0.5 + dv),
+            object_point: Point3::new(du, dv, 0.0),
+            world_point: Point3::new(du, dv, 0.0),
+        };
+        material.shading_normal(&coord_at(0.0, 0.0), Vector3::new(0.0, 0.0, 1.0), Vector3::new(1.0, 0.0, 0.0),
+                                Vector3::new(0.0, 1.0, 0.0), &coord_at)
+    }
+
+    fn assert_close(actual: Vector3<f64>, expected: Vector3<f64>) {
+        assert!((actual - expected).magnitude() < 1e-4, "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn bump_slope_tilts_the_normal() {
+        let mut material = Material::from_color(Color::new(1.0, 1.0, 1.0));
+        material.bump_map = Some(Arc::new(ramp_u()));
+        material.bump_scale = 1.0;
+        // The surface rises by 0.5 per unit along +x, so the normal leans back towards -x
+        assert_close(shade_flat(&material), Vector3::new(-0.5, 0.0, 1.0).normalize());
+    }
+
+    #[test]
+    fn normal_map_is_read_in_tangent_space() {
+        let mut material = Material::from_color(Color::new(1.0, 1.0, 1.0));
+        // Encodes (0.6, 0, 0.8), tilted towards the tangent along dpdu
+        material.normal_map = Some(Arc::new(ConstantTexture::new(Color::new(0.8, 0.5, 0.9))));
+        assert_close(shade_flat(&material), Vector3::new(0.6, 0.0, 0.8));
+        assert_close(shade_flat(&Material::from_color(Color::new(1.0, 1.0, 1.0))), Vector3::new(0.0, 0.0, 1.0));
+    }
+}
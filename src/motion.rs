@@ -0,0 +1,181 @@
+use cgmath::{Matrix3, Matrix4, Quaternion, Vector3, InnerSpace, SquareMatrix};
+
+/// Transform keyframes over time, interpolated by translation, rotation and scale.
+///
+/// Times are in the same units as the camera shutter, outside the keys the first or last transform holds.
+#[derive(Clone)]
+pub struct Motion {
+    keys: Vec<(f64, Matrix4<f64>)>,
+}
+
+impl Motion {
+    /// Motion through the given `(time, transform)` keys, which need not be sorted.
+    pub fn new(keys: Vec<(f64, Matrix4<f64>)>) -> Motion {
+        assert!(!keys.is_empty(), "motion needs at least one key");
+        let mut keys = keys;
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Motion {
+            keys: keys,
+        }
+    }
+
+    /// Moves from `start` at time 0 to `end` at time 1.
+    pub fn linear(start: Matrix4<f64>, end: Matrix4<f64>) -> Motion {
+        Motion::new(vec![(0.0, start), (1.0, end)])
+    }
+
+    pub fn keys(&self) -> &[(f64, Matrix4<f64>)] {
+        &self.keys
+    }
+
+    /// Transform at `time`, the stored key itself at key times.
+    pub fn at(&self, time: f64) -> Matrix4<f64> {
+        let next = self.keys.partition_point(|key| key.0 <= time);
+        if next == 0 {
+            return self.keys[0].1;
+        }
+        let (t0, a) = self.keys[next - 1];
+        if next == self.keys.len() || t0 == time {
+            return a;
+        }
+        let (t1, b) = self.keys[next];
+        interpolate(&a, &b, (time - t0) / (t1 - t0))
+    }
+}
+
+/// Transform through nested frames of which some move, like a node below moving ancestors.
+///
+/// Each frame is interpolated on its own and their product taken at every time, so the shear a turning frame
+/// gets below a non-uniformly scaled one is kept rather than blended away.
+#[derive(Clone, Default)]
+pub struct MotionChain {
+    /// Frames from the outermost inwards, neighbouring static frames are merged into one key.
+    frames: Vec<Motion>,
+    times: Vec<f64>,
+}
+
+impl MotionChain {
+    /// Appends the next inner frame, animated by `motion` if set and fixed at `transform` otherwise.
+    pub fn push(&mut self, transform: Matrix4<f64>, motion: Option<&Motion>) {
+        match motion {
+            Some(motion) => {
+                self.times.extend(motion.keys().iter().map(|key| key.0));
+                self.times.sort_by(|a, b| a.total_cmp(b));
+                self.times.dedup();
+                self.frames.push(motion.clone());
+            }
+            None => match self.frames.last_mut() {
+                Some(last) if last.keys.len() == 1 => last.keys[0].1 = last.keys[0].1 * transform,
+                _ => self.frames.push(Motion::new(vec![(0.0, transform)])),
+            },
+        }
+    }
+
+    /// Key times of all frames, sorted and empty if none of them moves.
+    pub fn times(&self) -> &[f64] {
+        &self.times
+    }
+
+    pub fn at(&self, time: f64) -> Matrix4<f64> {
+        self.frames.iter().fold(Matrix4::identity(), |transform, frame| transform * frame.at(time))
+    }
+}
+
+/// Blend between two transforms without shear, rotations take the shorter way round.
+pub fn interpolate(a: &Matrix4<f64>, b: &Matrix4<f64>, amount: f64) -> Matrix4<f64> {
+    let (ta, ra, sa) = decompose(a);
+    let (tb, rb, sb) = decompose(b);
+    let rb = if ra.dot(rb) < 0.0 { -rb } else { rb };
+    let translation = ta + (tb - ta) * amount;
+    let scale = sa + (sb - sa) * amount;
+    Matrix4::from_translation(translation) * Matrix4::from(ra.slerp(rb, amount).normalize())
+        * Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z)
+}
+
+/// Translation, rotation and scale of an affine transform, mirroring is kept in the x scale.
+fn decompose(m: &Matrix4<f64>) -> (Vector3<f64>, Quaternion<f64>, Vector3<f64>) {
+    let linear = Matrix3::from_cols(m.x.truncate(), m.y.truncate(), m.z.truncate());
+    let mut scale = Vector3::new(linear.x.magnitude(), linear.y.magnitude(), linear.z.magnitude());
+    if linear.determinant() < 0.0 {
+        scale.x = -scale.x;
+    }
+    let axis = |column: Vector3<f64>, s: f64| if s != 0.0 { column / s } else { column };
+    let rotation = Matrix3::from_cols(axis(linear.x, scale.x), axis(linear.y, scale.y), axis(linear.z, scale.z));
+    (m.w.truncate(), Quaternion::from(rotation), scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Deg;
+
+    fn assert_close(actual: Matrix4<f64>, expected: Matrix4<f64>) {
+        let (a, e): (&[f64; 16], &[f64; 16]) = (actual.as_ref(), expected.as_ref());
+        assert!(a.iter().zip(e.iter()).all(|(a, e)| (a - e).abs() < 1e-9), "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn keys_are_sorted_and_clamped() {
+        let start = Matrix4::from_translation(Vector3::new(0.0, 0.0, 0.0));
+        let end = Matrix4::from_translation(Vector3::new(4.0, 0.0, 0.0));
+        let motion = Motion::new(vec![(1.0, end), (-1.0, start)]);
+        assert_close(motion.at(-5.0), start);
+        assert_close(motion.at(5.0), end);
+        assert_close(motion.at(0.5), Matrix4::from_translation(Vector3::new(3.0, 0.0, 0.0)));
+        // A single key holds for all times
+        assert_close(Motion::new(vec![(0.0, end)]).at(0.5), end);
+    }
+
+    #[test]
+    fn keys_are_returned_exactly() {
+        // Shear does not survive interpolation, a key holding it must still come back unchanged
+        let sheared = Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0) * Matrix4::from_angle_z(Deg(30.0));
+        let motion = Motion::new(vec![(0.0, sheared), (0.5, Matrix4::identity()), (1.0, sheared)]);
+        assert_eq!(motion.at(0.0), sheared);
+        assert_eq!(motion.at(1.0), sheared);
+        assert_eq!(motion.at(0.5), Matrix4::identity());
+    }
+
+    #[test]
+    fn chains_keep_the_shear_between_frames() {
+        let scale = Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0);
+        let offset = Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0));
+        let turn = Motion::linear(Matrix4::identity(), Matrix4::from_angle_z(Deg(90.0)));
+        let mut chain = MotionChain::default();
+        chain.push(Matrix4::from_translation(Vector3::new(0.0, 3.0, 0.0)), None);
+        chain.push(scale, None);
+        chain.push(Matrix4::identity(), Some(&turn));
+        chain.push(offset, None);
+        assert_eq!(chain.times(), [0.0, 1.0]);
+        let lift = Matrix4::from_translation(Vector3::new(0.0, 3.0, 0.0));
+        assert_close(chain.at(0.5), lift * scale * Matrix4::from_angle_z(Deg(45.0)) * offset);
+        assert_close(chain.at(1.0), lift * scale * Matrix4::from_angle_z(Deg(90.0)) * offset);
+        // Without any motion the chain is just the product
+        let mut fixed = MotionChain::default();
+        fixed.push(lift, None);
+        fixed.push(scale, None);
+        assert!(fixed.times().is_empty());
+        assert_close(fixed.at(0.3), lift * scale);
+    }
+
+    #[test]
+    fn translation_and_scale_blend_linearly() {
+        let a = Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0)) * Matrix4::from_nonuniform_scale(1.0, 2.0, 4.0);
+        let b = Matrix4::from_translation(Vector3::new(3.0, 2.0, -1.0)) * Matrix4::from_nonuniform_scale(3.0, 2.0, 2.0);
+        let expected = Matrix4::from_translation(Vector3::new(2.0, 2.0, 1.0)) * Matrix4::from_nonuniform_scale(2.0, 2.0, 3.0);
+        assert_close(interpolate(&a, &b, 0.5), expected);
+        assert_close(interpolate(&a, &b, 0.0), a);
+        assert_close(interpolate(&a, &b, 1.0), b);
+    }
+
+    #[test]
+    fn rotation_takes_the_shorter_way() {
+        // 270 degrees round y is the same as -90, halfway lies at -45 rather than 135
+        let a = Matrix4::from_angle_y(Deg(0.0));
+        let b = Matrix4::from_angle_y(Deg(270.0));
+        assert_close(interpolate(&a, &b, 0.5), Matrix4::from_angle_y(Deg(-45.0)));
+        // Scale is blended apart from the rotation
+        let b = Matrix4::from_angle_z(Deg(90.0)) * Matrix4::from_scale(3.0);
+        assert_close(interpolate(&a, &b, 0.5), Matrix4::from_angle_z(Deg(45.0)) * Matrix4::from_scale(2.0));
+    }
+}
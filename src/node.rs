@@ -1,15 +1,429 @@
-use std::rc::Rc;
+use std::rc::{Weak, Rc};
 use std::cell::RefCell;
-use crate::{Ray, Color};
-use cgmath::{Vector3, Matrix4};
+use crate::{Material, Node3D, Shape, CompiledLight, CompiledCamera, Motion};
+use crate::compiled_scene::SceneCompiler;
+use std::sync::Arc;
+use cgmath::{Matrix4, SquareMatrix};
 
+/// Hierarchy and transform state shared by every node type.
+pub struct NodeCore {
+    childs: Vec<Rc<RefCell<dyn Node>>>,
+    parent: Weak<RefCell<dyn Node>>,
+    dirty: bool,
+    pub name: String,
+    pub frame_transform: Matrix4<f64>,
+    /// Keyframed frame transform for motion blur, `frame_transform` holds its first key.
+    pub frame_motion: Option<Motion>,
+    pub world_transform: Matrix4<f64>,
+    /// Inverse of `world_transform`, identity if that is singular.
+    pub inverse_transform: Matrix4<f64>,
+}
+
+impl NodeCore {
+    pub fn new(name: String, transform: Matrix4<f64>) -> NodeCore {
+        NodeCore {
+            childs: vec![],
+            parent: no_parent(),
+            dirty: false,
+            name: name,
+            frame_transform: transform,
+            frame_motion: None,
+            world_transform: transform,
+            inverse_transform: transform.invert().unwrap_or(Matrix4::identity()),
+        }
+    }
+
+    pub fn children(&self) -> &[Rc<RefCell<dyn Node>>] {
+        &self.childs
+    }
+
+    /// Transform relative to the parent at `time`.
+    pub fn frame_transform_at(&self, time: f64) -> Matrix4<f64> {
+        match self.frame_motion {
+            Some(ref motion) => motion.at(time),
+            None => self.frame_transform,
+        }
+    }
+}
+
+/// Node types only provide their `NodeCore` and geometry, the hierarchy methods work on the core.
+///
+/// Nodes are the authoring side of a scene, the renderer reads the `CompiledScene` built from them.
 pub trait Node {
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>>;
-    fn get_child(&self, index: usize) -> Option<Rc<RefCell<Node>>>;
-    fn add_child(&mut self, node: Rc<RefCell<Node>>);
-    fn get_color(&self) -> Color;
-    fn get_size(&self) -> usize;
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vector3<f64>)>;
-    fn get_world_transform(&self) -> Matrix4<f64>;
-    fn set_world_transform(&mut self, transform: &Matrix4<f64>) -> ();
-}
\ No newline at end of file
+    fn core(&self) -> &NodeCore;
+    fn core_mut(&mut self) -> &mut NodeCore;
+    /// Material of the node's shape, `None` for nodes without one of their own.
+    fn get_material(&self) -> Option<Arc<Material>> {
+        return None;
+    }
+    /// Geometry of the node in its own object space, `None` for nodes that only group or place others.
+    fn get_shape(&self, _compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        return None;
+    }
+    /// Light emitted by the node, placed by its world transform.
+    fn get_light(&self) -> Option<CompiledLight> {
+        return None;
+    }
+    /// Camera the node renders through, placed by its world transform.
+    fn get_camera(&self) -> Option<CompiledCamera> {
+        return None;
+    }
+    /// True if `get_shape` already covers the children, compilation then does not visit them on its own.
+    fn shape_includes_children(&self) -> bool {
+        return false;
+    }
+
+    fn get_name(&self) -> String {
+        return self.core().name.clone();
+    }
+    fn get_parent(&self) -> Option<Rc<RefCell<dyn Node>>> {
+        return self.core().parent.upgrade();
+    }
+    /// Only replaces the parent link, the free `add_child` and `remove_child` keep both sides in sync.
+    fn set_parent(&mut self, parent: Weak<RefCell<dyn Node>>) {
+        self.core_mut().parent = parent;
+    }
+    fn get_child(&self, index: usize) -> Option<Rc<RefCell<dyn Node>>> {
+        return self.core().childs.get(index).map(Rc::clone);
+    }
+    fn get_size(&self) -> usize {
+        return self.core().childs.len();
+    }
+    fn get_world_transform(&self) -> Matrix4<f64> {
+        return self.core().world_transform;
+    }
+    /// Transform relative to the parent.
+    fn get_frame_transform(&self) -> Matrix4<f64> {
+        return self.core().frame_transform;
+    }
+    /// Replaces the transform relative to the parent, the world transforms of the subtree follow on the next
+    /// `update_transforms`.
+    fn set_frame_transform(&mut self, transform: &Matrix4<f64>) {
+        let core = self.core_mut();
+        core.frame_transform = *transform;
+        core.dirty = true;
+    }
+    /// Animates the transform relative to the parent for motion blur, `None` makes the node static again at
+    /// its current frame transform.
+    fn set_frame_motion(&mut self, motion: Option<Motion>) {
+        let core = self.core_mut();
+        if let Some(ref motion) = motion {
+            core.frame_transform = motion.keys()[0].1;
+        }
+        core.frame_motion = motion;
+        core.dirty = true;
+    }
+    /// True if the frame transform changed since the world transform was last computed.
+    fn is_dirty(&self) -> bool {
+        return self.core().dirty;
+    }
+    /// Recomputes the world transform from the parent's world transform and clears the dirty flag.
+    fn set_world_transform(&mut self, transform: &Matrix4<f64>) {
+        let core = self.core_mut();
+        core.world_transform = transform * core.frame_transform;
+        core.inverse_transform = core.world_transform.invert().unwrap_or(Matrix4::identity());
+        core.dirty = false;
+    }
+}
+
+/// Parent link of a node that is not attached to anything.
+pub fn no_parent() -> Weak<RefCell<dyn Node>> {
+    return Weak::<RefCell<Node3D>>::new();
+}
+
+/// Attaches `child` below `parent`, links it back to its parent and places the whole subtree under it.
+///
+/// A child that already has a parent is detached from it first, its frame transform is kept. Returns false and
+/// leaves the graph untouched if `parent` lies inside the subtree of `child`.
+pub fn add_child(parent: &Rc<RefCell<dyn Node>>, child: Rc<RefCell<dyn Node>>) -> bool {
+    if contains(&child, parent) {
+        return false;
+    }
+    let old_parent = value!(child).get_parent();
+    if let Some(old_parent) = old_parent {
+        let index = child_index(&old_parent, &child);
+        if let Some(index) = index {
+            value!(old_parent).core_mut().childs.remove(index);
+        }
+    }
+    value!(child).set_parent(Rc::downgrade(parent));
+    value!(parent).core_mut().childs.push(Rc::clone(&child));
+    propagate(&child, &world_transform(parent), true);
+    return true;
+}
+
+/// True if `node` lies at or below `root`.
+fn contains(root: &Rc<RefCell<dyn Node>>, node: &Rc<RefCell<dyn Node>>) -> bool {
+    return find(root, &mut |x| Rc::ptr_eq(x, node)).is_some();
+}
+
+/// World transform of `node` from the frame transforms up to the root, unlike `get_world_transform` it is
+/// right even while an ancestor is dirty.
+pub fn world_transform(node: &Rc<RefCell<dyn Node>>) -> Matrix4<f64> {
+    let (frame, parent) = {
+        let node = value!(node);
+        (node.get_frame_transform(), node.get_parent())
+    };
+    match parent {
+        Some(parent) => world_transform(&parent) * frame,
+        None => frame,
+    }
+}
+
+/// Brings the world transforms below `node` up to date, only subtrees with a changed frame transform are
+/// recomputed.
+pub fn update_transforms(node: &Rc<RefCell<dyn Node>>) {
+    let parent = value!(node).get_parent();
+    let world = match parent {
+        Some(parent) => value!(parent).get_world_transform(),
+        None => Matrix4::identity(),
+    };
+    propagate(node, &world, false);
+}
+
+fn propagate(node: &Rc<RefCell<dyn Node>>, parent_world: &Matrix4<f64>, force: bool) {
+    let force = force || value!(node).is_dirty();
+    if force {
+        value!(node).set_world_transform(parent_world);
+    }
+    let world = value!(node).get_world_transform();
+    let size = value!(node).get_size();
+    for i in 0..size {
+        if let Some(child) = value!(node).get_child(i) {
+            propagate(&child, &world, force);
+        }
+    }
+}
+
+/// Detaches `child` from `parent`, the subtree stays where its frame transforms put it without a parent.
+/// Returns false if `child` is not a direct child of `parent`.
+pub fn remove_child(parent: &Rc<RefCell<dyn Node>>, child: &Rc<RefCell<dyn Node>>) -> bool {
+    let index = match child_index(parent, child) {
+        Some(x) => x,
+        None => return false,
+    };
+    value!(parent).core_mut().childs.remove(index);
+    value!(child).set_parent(no_parent());
+    propagate(child, &Matrix4::identity(), true);
+    return true;
+}
+
+/// Moves `child` below `new_parent` and adjusts its frame transform so it keeps its place in the world.
+/// Returns false and leaves the graph untouched if `new_parent` lies inside the subtree of `child`.
+pub fn reparent(child: &Rc<RefCell<dyn Node>>, new_parent: &Rc<RefCell<dyn Node>>) -> bool {
+    if contains(child, new_parent) {
+        return false;
+    }
+    let world = world_transform(child);
+    let parent_world = world_transform(new_parent);
+    let frame = parent_world.invert().unwrap_or(Matrix4::identity()) * world;
+    value!(child).set_frame_transform(&frame);
+    add_child(new_parent, Rc::clone(child));
+    return true;
+}
+
+/// Position of `child` among the children of `parent`.
+pub fn child_index(parent: &Rc<RefCell<dyn Node>>, child: &Rc<RefCell<dyn Node>>) -> Option<usize> {
+    let size = value!(parent).get_size();
+    for i in 0..size {
+        let current = value!(parent).get_child(i);
+        if current.is_some_and(|x| Rc::ptr_eq(&x, child)) {
+            return Some(i);
+        }
+    }
+    return None;
+}
+
+/// Calls `visitor` for `node` and every node below it depth-first, parents before their children, together with
+/// the depth below `node`.
+pub fn visit<F: FnMut(&Rc<RefCell<dyn Node>>, usize)>(node: &Rc<RefCell<dyn Node>>, visitor: &mut F) {
+    visit_at(node, 0, visitor);
+}
+
+fn visit_at<F: FnMut(&Rc<RefCell<dyn Node>>, usize)>(node: &Rc<RefCell<dyn Node>>, depth: usize, visitor: &mut F) {
+    visitor(node, depth);
+    let size = value!(node).get_size();
+    for i in 0..size {
+        let child = value!(node).get_child(i);
+        if let Some(child) = child {
+            visit_at(&child, depth + 1, visitor);
+        }
+    }
+}
+
+/// The nodes from the root down to `node`, both included.
+pub fn chain_from_root(node: &Rc<RefCell<dyn Node>>) -> Vec<Rc<RefCell<dyn Node>>> {
+    let mut chain = vec![];
+    let mut current = Some(Rc::clone(node));
+    while let Some(node) = current {
+        current = value!(node).get_parent();
+        chain.push(node);
+    }
+    chain.reverse();
+    chain
+}
+
+/// `node` and every node below it in depth-first order.
+pub fn descendants(node: &Rc<RefCell<dyn Node>>) -> Vec<Rc<RefCell<dyn Node>>> {
+    let mut nodes = vec![];
+    visit(node, &mut |x, _| nodes.push(Rc::clone(x)));
+    return nodes;
+}
+
+/// First node in depth-first order at or below `node` that matches `predicate`.
+pub fn find<F: FnMut(&Rc<RefCell<dyn Node>>) -> bool>(node: &Rc<RefCell<dyn Node>>, predicate: &mut F)
+        -> Option<Rc<RefCell<dyn Node>>> {
+    if predicate(node) {
+        return Some(Rc::clone(node));
+    }
+    let size = value!(node).get_size();
+    for i in 0..size {
+        let child = value!(node).get_child(i);
+        if let Some(found) = child.and_then(|child| find(&child, predicate)) {
+            return Some(found);
+        }
+    }
+    return None;
+}
+
+/// First node named `name` at or below `node`.
+pub fn find_by_name(node: &Rc<RefCell<dyn Node>>, name: &str) -> Option<Rc<RefCell<dyn Node>>> {
+    return find(node, &mut |x| value!(x).get_name() == name);
+}
+
+/// Follows a slash separated list of names starting with the name of `node` itself, e.g.
+/// `root/Sphere root/Sphere Red`.
+pub fn find_by_path(node: &Rc<RefCell<dyn Node>>, path: &str) -> Option<Rc<RefCell<dyn Node>>> {
+    let mut names = path.split('/').filter(|x| !x.is_empty());
+    if names.next()? != value!(node).get_name() {
+        return None;
+    }
+    let mut current = Rc::clone(node);
+    for name in names {
+        let size = value!(current).get_size();
+        let next = (0..size)
+            .filter_map(|i| value!(current).get_child(i))
+            .find(|x| value!(x).get_name() == name)?;
+        current = next;
+    }
+    return Some(current);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Vector3;
+
+    fn node(name: &str, x: f64) -> Rc<RefCell<dyn Node>> {
+        Rc::new(RefCell::new(Node3D::new(name.to_string(), Matrix4::from_translation(Vector3::new(x, 0.0, 0.0)))))
+    }
+
+    fn world_x(node: &Rc<RefCell<dyn Node>>) -> f64 {
+        value!(node).get_world_transform().w.x
+    }
+
+    #[test]
+    fn frame_changes_reach_the_subtree_on_update() {
+        let (root, parent, child, grandchild) = (node("root", 1.0), node("parent", 2.0), node("child", 3.0), node("grand", 4.0));
+        add_child(&child, Rc::clone(&grandchild));
+        add_child(&parent, Rc::clone(&child));
+        add_child(&root, Rc::clone(&parent));
+        assert!((world_x(&grandchild) - 10.0).abs() < 1e-12);
+        assert!(!value!(parent).is_dirty());
+
+        value!(parent).set_frame_transform(&Matrix4::from_translation(Vector3::new(5.0, 0.0, 0.0)));
+        assert!(value!(parent).is_dirty());
+        // Cached world transforms wait for the update, the computed one does not
+        assert!((world_x(&grandchild) - 10.0).abs() < 1e-12);
+        assert!((world_transform(&grandchild).w.x - 13.0).abs() < 1e-12);
+
+        update_transforms(&root);
+        assert!(!value!(parent).is_dirty());
+        assert!((world_x(&parent) - 6.0).abs() < 1e-12);
+        assert!((world_x(&child) - 9.0).abs() < 1e-12);
+        assert!((world_x(&grandchild) - 13.0).abs() < 1e-12);
+        let inverse = value!(grandchild).core().inverse_transform;
+        assert!((inverse.w.x + 13.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn reparent_sees_a_dirty_old_parent() {
+        let (root, old_parent, new_parent, child) = (node("root", 0.0), node("old", 5.0), node("new", 1.0), node("child", 0.0));
+        add_child(&old_parent, Rc::clone(&child));
+        add_child(&root, Rc::clone(&old_parent));
+        add_child(&root, Rc::clone(&new_parent));
+
+        value!(old_parent).set_frame_transform(&Matrix4::from_translation(Vector3::new(7.0, 0.0, 0.0)));
+        assert!(reparent(&child, &new_parent));
+        update_transforms(&root);
+        assert!((world_x(&child) - 7.0).abs() < 1e-12);
+        assert_eq!(value!(old_parent).get_size(), 0);
+    }
+
+    #[test]
+    fn reparent_sees_a_dirty_new_parent() {
+        let (root, grandparent, new_parent, child) = (node("root", 0.0), node("grand", 0.0), node("new", 1.0), node("child", 2.0));
+        add_child(&grandparent, Rc::clone(&new_parent));
+        add_child(&root, Rc::clone(&grandparent));
+        add_child(&root, Rc::clone(&child));
+
+        value!(grandparent).set_frame_transform(&Matrix4::from_translation(Vector3::new(3.0, 0.0, 0.0)));
+        assert!(reparent(&child, &new_parent));
+        // Right away and after the pending update
+        assert!((world_x(&child) - 2.0).abs() < 1e-12);
+        update_transforms(&root);
+        assert!((world_x(&child) - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn reparent_into_own_subtree_is_refused() {
+        let (root, parent, child) = (node("root", 0.0), node("parent", 1.0), node("child", 1.0));
+        add_child(&parent, Rc::clone(&child));
+        add_child(&root, Rc::clone(&parent));
+
+        assert!(!reparent(&parent, &child));
+        assert!(!reparent(&parent, &parent));
+        assert!(Rc::ptr_eq(&value!(parent).get_parent().unwrap(), &root));
+        assert_eq!(value!(root).get_size(), 1);
+        assert!((world_x(&child) - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn add_child_detaches_from_previous_parent() {
+        let (first, second, child) = (node("first", 0.0), node("second", 0.0), node("child", 0.0));
+        add_child(&first, Rc::clone(&child));
+        add_child(&second, Rc::clone(&child));
+        assert_eq!(value!(first).get_size(), 0);
+        assert_eq!(value!(second).get_size(), 1);
+        assert!(Rc::ptr_eq(&value!(child).get_parent().unwrap(), &second));
+    }
+
+    #[test]
+    fn add_child_refuses_cycles() {
+        let (root, parent, child) = (node("root", 0.0), node("parent", 1.0), node("child", 1.0));
+        add_child(&parent, Rc::clone(&child));
+        assert!(add_child(&root, Rc::clone(&parent)));
+
+        assert!(!add_child(&child, Rc::clone(&root)));
+        assert!(!add_child(&child, Rc::clone(&child)));
+        assert!(value!(root).get_parent().is_none());
+        assert_eq!(value!(child).get_size(), 0);
+        assert!(Rc::ptr_eq(&value!(child).get_parent().unwrap(), &parent));
+        assert_eq!(descendants(&root).len(), 3);
+    }
+
+    #[test]
+    fn find_by_path_follows_names_from_the_root() {
+        let (root, group, leaf) = (node("root", 0.0), node("Sphere root", 0.0), node("Sphere Red", 0.0));
+        add_child(&group, Rc::clone(&leaf));
+        add_child(&root, Rc::clone(&group));
+
+        let found = find_by_path(&root, "root/Sphere root/Sphere Red").unwrap();
+        assert!(Rc::ptr_eq(&found, &leaf));
+        assert!(Rc::ptr_eq(&find_by_path(&root, "/root/Sphere root/").unwrap(), &group));
+        assert!(Rc::ptr_eq(&find_by_path(&root, "root").unwrap(), &root));
+        assert!(find_by_path(&root, "Sphere root/Sphere Red").is_none());
+        assert!(find_by_path(&root, "root/Sphere Red").is_none());
+        assert!(find_by_path(&root, "").is_none());
+    }
+}
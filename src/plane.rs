@@ -1,126 +1,201 @@
-use crate::{Node, Ray, Color};
-use std::rc::{Weak, Rc};
-use cgmath::{InnerSpace, Matrix4, Matrix3, Matrix, Vector3, SquareMatrix, EuclideanSpace};
-use std::cell::RefCell;
+use crate::{Node, Ray, Color, Material, HitRecord, Aabb, Shape, SurfaceSample};
+use crate::compiled_scene::SceneCompiler;
+use crate::node::NodeCore;
+use crate::hit_record::gamma;
+use std::sync::Arc;
+use std::io;
+use cgmath::{Matrix4, Vector2, Vector3, Point3};
+use std::f64::consts::PI;
+
+/// Extent of a `Plane` inside its local xz plane, centered on the local origin.
+#[derive(Clone)]
+pub enum PlaneShape {
+    Infinite,
+    /// Spans `width` along the local x axis and `height` along the local z axis.
+    Rectangle { width: f64, height: f64 },
+    /// Annulus if `inner_radius` is greater than zero, which must stay below `radius`.
+    Disk { radius: f64, inner_radius: f64 },
+}
 
+/// Planar primitive in the local xz plane, visible from its +y side.
 pub struct Plane {
-    childs: Vec<Rc<RefCell<Node>>>,
-    parent: Weak<Rc<RefCell<Node>>>,
-    size: usize,
-    pub name: String,
-    pub frame_transform: Matrix4<f64>,
-    pub world_transform: Matrix4<f64>,
-    //pub origin: Point3<f64>,
-    //pub normal: Vector3<f64>,
-    pub color: Color,
+    pub core: NodeCore,
+    pub shape: Arc<PlaneShape>,
+    pub material: Arc<Material>,
 }
 
 impl Plane {
+    /// Infinite plane.
     pub fn new(name: String, transform: Matrix4<f64>, color: Color) -> Plane {
+        Plane::from_shape(name, transform, PlaneShape::Infinite, color)
+    }
+
+    pub fn rectangle(name: String, transform: Matrix4<f64>, width: f64, height: f64, color: Color) -> Plane {
+        Plane::from_shape(name, transform, PlaneShape::Rectangle { width: width, height: height }, color)
+    }
+
+    /// See `with_shape` for when this fails.
+    pub fn disk(name: String, transform: Matrix4<f64>, radius: f64, inner_radius: f64, color: Color) -> io::Result<Plane> {
+        Plane::with_shape(name, transform, PlaneShape::Disk { radius: radius, inner_radius: inner_radius }, color)
+    }
+
+    /// Fails with `InvalidInput` for a disk that does not satisfy `0 <= inner_radius < radius`.
+    pub fn with_shape(name: String, transform: Matrix4<f64>, shape: PlaneShape, color: Color) -> io::Result<Plane> {
+        if let PlaneShape::Disk { radius, inner_radius } = shape {
+            if !(0.0 <= inner_radius && inner_radius < radius) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "disk {} needs 0 <= inner_radius < radius, got {} and {}", name, inner_radius, radius)));
+            }
+        }
+        Ok(Plane::from_shape(name, transform, shape, color))
+    }
+
+    fn from_shape(name: String, transform: Matrix4<f64>, shape: PlaneShape, color: Color) -> Plane {
         Plane {
-            childs: vec![],
-            parent: Weak::new(),
-            size: 0,
-            name: name,
-            frame_transform: transform,
-            world_transform: transform,
-            //origin: origin,
-            //normal: normal,
-            color: color,
+            core: NodeCore::new(name, transform),
+            shape: Arc::new(shape),
+            material: Arc::new(Material::from_color(color)),
         }
     }
 }
 
-impl Node for Plane {
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        let strong = &self.parent.upgrade();
-        let strong = match strong {
-            Some(x) => x,
-            None => return None,
-        };
-        return Some(Rc::clone(&(*strong))); //Some(Rc::clone(&(*(&self.parent))));
+impl PlaneShape {
+    /// UV and its position derivatives at the local point `(x, 0, z)`, `None` if the point lies outside the shape.
+    fn surface_frame(&self, x: f64, z: f64) -> Option<(Vector2<f64>, Vector3<f64>, Vector3<f64>)> {
+        match *self {
+            // Planar mapping from the local x and z coordinates, one UV unit per local unit
+            PlaneShape::Infinite => Some((Vector2::new(x, z), Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0))),
+            PlaneShape::Rectangle { width, height } => {
+                if x.abs() > width * 0.5 || z.abs() > height * 0.5 {
+                    return None;
+                }
+                // The rectangle covers the unit square in UV space
+                Some((Vector2::new(x / width + 0.5, z / height + 0.5),
+                      Vector3::new(width, 0.0, 0.0), Vector3::new(0.0, 0.0, height)))
+            }
+            PlaneShape::Disk { radius, inner_radius } => {
+                let r2 = x * x + z * z;
+                if r2 > radius * radius || r2 < inner_radius * inner_radius {
+                    return None;
+                }
+                // Polar mapping, u runs around the disk and v from the outer to the inner edge
+                let r = r2.sqrt();
+                let phi = z.atan2(x).rem_euclid(2.0 * PI);
+                let dpdu = Vector3::new(-z, 0.0, x) * (2.0 * PI);
+                let dpdv = if r > 0.0 {
+                    Vector3::new(-x, 0.0, -z) * ((radius - inner_radius) / r)
+                } else {
+                    Vector3::new(-(radius - inner_radius), 0.0, 0.0)
+                };
+                Some((Vector2::new(phi / (2.0 * PI), (radius - r) / (radius - inner_radius)), dpdu, dpdv))
+            }
+        }
+    }
+}
+
+impl Shape for PlaneShape {
+    /// Intersects the local xz plane from its +y side and clips the hit to the shape.
+    fn intersect_all(&self, local_ray: &Ray) -> Vec<HitRecord> {
+        self.intersect(local_ray).into_iter().collect()
     }
 
-    fn get_child(&self, index: usize) -> Option<Rc<RefCell<Node>>> {
-        if index >= self.size {
+    fn intersect(&self, local_ray: &Ray) -> Option<HitRecord> {
+        if local_ray.direction.y >= 0.0 {
             return None;
         }
-        return Some(Rc::clone(&(self.childs[index])));
-    }
-
-    fn add_child(&mut self, node: Rc<RefCell<Node>>) {
-        self.size += 1;
-        value!(node).set_world_transform(&self.world_transform);
-        self.childs.push(Rc::clone(&node));
-    }
-
-    fn get_size(&self) -> usize {
-        return self.size;
-    }
-
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vector3<f64>)> {
-        let view_transpose = Matrix3::from_cols(
-            self.world_transform.x.clone().truncate(),
-            self.world_transform.y.clone().truncate(),
-            self.world_transform.z.clone().truncate(),
-        );
-
-        let normal =  view_transpose.invert().unwrap().transpose() * Vector3::new(0.0, 1.0, 0.0);
-        let denom = normal.dot(-ray.direction);
-        if denom > 1e-6 {    
-            let origin = self.world_transform.w.truncate();
-            let v = ray.origin - origin;
-            let distance = v.dot(normal) / denom;
-            if distance >= 0.0 {
-                let hit_point = ray.origin + (ray.direction * distance);
-                let l =  view_transpose.invert().unwrap() * (hit_point.to_vec() - origin);
-                //let l = l.magnitude();
-                if l.x.abs() < 4.0 && l.y.abs() < 4.0 && l.z.abs() < 4.0 {
-                    return Some((distance, -normal));
-                }
-            }
+
+        let distance = -local_ray.origin.y / local_ray.direction.y;
+        if !local_ray.contains(distance) {
+            return None;
         }
-        None
+        let l = local_ray.origin + local_ray.direction * distance;
+        let (uv, dpdu, dpdv) = self.surface_frame(l.x, l.z)?;
+
+        let mut hit = HitRecord::new(local_ray, distance, Vector3::new(0.0, 1.0, 0.0), uv, dpdu, dpdv, None);
+        // Lying exactly in the plane the local y coordinate carries no error
+        hit.position = Point3::new(l.x, 0.0, l.z);
+        hit.error = Vector3::new(l.x.abs(), 0.0, l.z.abs()) * gamma(7);
+        Some(hit)
     }
 
-    fn get_color(&self) -> Color {
-        return self.color.copy();
+    fn bounds(&self) -> Option<Aabb> {
+        let half_extents = match *self {
+            PlaneShape::Infinite => return None,
+            PlaneShape::Rectangle { width, height } => Vector3::new(width * 0.5, 0.0, height * 0.5),
+            PlaneShape::Disk { radius, .. } => Vector3::new(radius, 0.0, radius),
+        };
+        Some(Aabb::centered(half_extents))
     }
 
-    fn get_world_transform(&self) -> Matrix4<f64> {
-        return self.world_transform;
+    /// Uniform over rectangles and disks, infinite planes cannot be sampled.
+    fn sample(&self, u: Vector2<f64>) -> Option<SurfaceSample> {
+        let (position, area, uv) = match *self {
+            PlaneShape::Infinite => return None,
+            PlaneShape::Rectangle { width, height } =>
+                (Point3::new((u.x - 0.5) * width, 0.0, (u.y - 0.5) * height), width * height, u),
+            PlaneShape::Disk { radius, inner_radius } => {
+                // Uniform in area between the two radii
+                let r2 = inner_radius * inner_radius + u.y * (radius * radius - inner_radius * inner_radius);
+                let phi = 2.0 * PI * u.x;
+                (Point3::new(r2.sqrt() * phi.cos(), 0.0, r2.sqrt() * phi.sin()),
+                 PI * (radius * radius - inner_radius * inner_radius),
+                 Vector2::new(u.x, (radius - r2.sqrt()) / (radius - inner_radius)))
+            }
+        };
+        Some(SurfaceSample::with_area(position, Vector3::new(0.0, 1.0, 0.0), area, uv))
     }
+}
 
-    fn set_world_transform(&mut self, transform: &Matrix4<f64>) -> () {
-        println!("\n\n------------- {}", self.name);
-        println!("------- transform -----------");
-        println!("[{}, {}, {}, {}]", self.frame_transform.x.x, self.frame_transform.y.x, self.frame_transform.z.x, self.frame_transform.w.x);
-        println!("[{}, {}, {}, {}]", self.frame_transform.x.y, self.frame_transform.y.y, self.frame_transform.z.y, self.frame_transform.w.y);
-        println!("[{}, {}, {}, {}]", self.frame_transform.x.z, self.frame_transform.y.z, self.frame_transform.z.z, self.frame_transform.w.z);
-        println!("[{}, {}, {}, {}]", self.frame_transform.x.w, self.frame_transform.y.w, self.frame_transform.z.w, self.frame_transform.w.w);
+impl Node for Plane {
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
 
-        self.world_transform = transform * self.frame_transform;
-        println!("------- Model -----------");
-        println!("[{}, {}, {}, {}]", self.world_transform.x.x, self.world_transform.y.x, self.world_transform.z.x, self.world_transform.w.x);
-        println!("[{}, {}, {}, {}]", self.world_transform.x.y, self.world_transform.y.y, self.world_transform.z.y, self.world_transform.w.y);
-        println!("[{}, {}, {}, {}]", self.world_transform.x.z, self.world_transform.y.z, self.world_transform.z.z, self.world_transform.w.z);
-        println!("[{}, {}, {}, {}]", self.world_transform.x.w, self.world_transform.y.w, self.world_transform.z.w, self.world_transform.w.w);
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
+    }
 
-        let view_transpose = Matrix3::from_cols(
-            self.world_transform.x.clone().truncate(),
-            self.world_transform.y.clone().truncate(),
-            self.world_transform.z.clone().truncate(),
-        ).invert().unwrap().transpose();
-        println!("-------view-----------");
-        println!("[{}, {}, {}]", view_transpose.x.x, view_transpose.y.x, view_transpose.z.x);
-        println!("[{}, {}, {}]", view_transpose.x.y, view_transpose.y.y, view_transpose.z.y);
-        println!("[{}, {}, {}]", view_transpose.x.z, view_transpose.y.z, view_transpose.z.z);
+    fn get_shape(&self, _compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        return Some(self.shape.clone());
+    }
 
-        let normal =  view_transpose * Vector3::new(0.0, 1.0, 0.0);
+    fn get_material(&self) -> Option<Arc<Material>> {
+        return Some(Arc::clone(&self.material));
+    }
+}
 
-        println!("-------normal-----------");
-        println!("[{}, {}, {}]", normal.x, normal.y, normal.z);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annulus_uv_runs_from_outer_to_inner_edge() {
+        let disk = PlaneShape::Disk { radius: 2.0, inner_radius: 1.0 };
+        let (outer, _, _) = disk.surface_frame(2.0, 0.0).unwrap();
+        let (inner, _, _) = disk.surface_frame(0.0, 1.0).unwrap();
+        assert!(outer.y.abs() < 1e-12 && (inner.y - 1.0).abs() < 1e-12);
+        assert!(disk.surface_frame(0.5, 0.0).is_none());
+    }
 
+    #[test]
+    fn samples_report_the_uv_of_a_hit() {
+        let shapes = [PlaneShape::Rectangle { width: 2.0, height: 3.0 }, PlaneShape::Disk { radius: 2.0, inner_radius: 0.5 }];
+        for shape in shapes.iter() {
+            for u in [Vector2::new(0.25, 0.75), Vector2::new(0.6, 0.1)].iter() {
+                let sample = shape.sample(*u).unwrap();
+                let (uv, _, _) = shape.surface_frame(sample.position.x, sample.position.z).unwrap();
+                assert!((uv - sample.uv).x.abs() < 1e-9 && (uv - sample.uv).y.abs() < 1e-9);
+            }
+        }
+    }
 
-    }  
-}
\ No newline at end of file
+    #[test]
+    fn degenerate_rings_are_rejected() {
+        let white = Color::new(1.0, 1.0, 1.0);
+        for (radius, inner_radius) in [(1.0, 1.0), (1.0, 2.0), (1.0, -0.5), (0.0, 0.0)].iter() {
+            let result = Plane::disk("Ring".to_string(), Matrix4::from_scale(1.0), *radius, *inner_radius, white.copy());
+            assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        }
+        assert!(Plane::disk("Ring".to_string(), Matrix4::from_scale(1.0), 1.0, 0.0, white).is_ok());
+    }
+}
@@ -0,0 +1,141 @@
+/// Real roots of `a t^2 + b t + c` in ascending order, `None` if there are none.
+///
+/// Uses the numerically stable form that avoids cancelling `b` against the square root.
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
+    if a == 0.0 {
+        if b == 0.0 {
+            return None;
+        }
+        return Some((-c / b, -c / b));
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let q = if b < 0.0 { -0.5 * (b - root) } else { -0.5 * (b + root) };
+    let (t0, t1) = if q == 0.0 { (0.0, 0.0) } else { (q / a, c / q) };
+    if t0 < t1 { Some((t0, t1)) } else { Some((t1, t0)) }
+}
+
+/// Evaluates the polynomial with `coefficients` ordered from the highest power down.
+pub fn evaluate(coefficients: &[f64], t: f64) -> f64 {
+    coefficients.iter().fold(0.0, |sum, c| sum * t + c)
+}
+
+fn derivative(coefficients: &[f64]) -> Vec<f64> {
+    let degree = coefficients.len() - 1;
+    coefficients[..degree].iter().enumerate().map(|(i, c)| c * (degree - i) as f64).collect()
+}
+
+/// Real roots of the polynomial inside `[lo, hi]` in ascending order, `coefficients` ordered from the highest power down.
+///
+/// The roots of the derivative split the interval into monotonic pieces, each holds at most one root which is
+/// found by bisection. Slower than closed forms like Ferrari's method but robust for any degree, which matters
+/// for the quartic of a torus.
+pub fn real_roots(coefficients: &[f64], lo: f64, hi: f64) -> Vec<f64> {
+    let start = coefficients.iter().position(|c| *c != 0.0).unwrap_or(coefficients.len());
+    let coefficients = &coefficients[start..];
+    if coefficients.len() < 2 || lo > hi {
+        return vec![];
+    }
+    if coefficients.len() == 2 {
+        let t = -coefficients[1] / coefficients[0];
+        return if t >= lo && t <= hi { vec![t] } else { vec![] };
+    }
+
+    let mut bounds = vec![lo];
+    bounds.extend(real_roots(&derivative(coefficients), lo, hi));
+    bounds.push(hi);
+
+    let mut roots: Vec<f64> = vec![];
+    for pair in bounds.windows(2) {
+        if let Some(t) = bisect(coefficients, pair[0], pair[1]) {
+            // A root at a shared bound shows up in both neighbouring pieces
+            if roots.last().is_none_or(|last| t - last > 1e-12 * t.abs().max(1.0)) {
+                roots.push(t);
+            }
+        }
+    }
+    roots
+}
+
+fn bisect(coefficients: &[f64], lo: f64, hi: f64) -> Option<f64> {
+    let (mut lo, mut hi) = (lo, hi);
+    let mut f_lo = evaluate(coefficients, lo);
+    let f_hi = evaluate(coefficients, hi);
+    if f_lo == 0.0 {
+        return Some(lo);
+    }
+    if f_hi == 0.0 {
+        return Some(hi);
+    }
+    if (f_lo > 0.0) == (f_hi > 0.0) {
+        return None;
+    }
+    for _ in 0..100 {
+        let mid = 0.5 * (lo + hi);
+        if mid <= lo || mid >= hi {
+            break;
+        }
+        let f_mid = evaluate(coefficients, mid);
+        if f_mid == 0.0 {
+            return Some(mid);
+        }
+        if (f_mid > 0.0) == (f_lo > 0.0) {
+            lo = mid;
+            f_lo = f_mid;
+        } else {
+            hi = mid;
+        }
+    }
+    Some(0.5 * (lo + hi))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_roots(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "roots {:?}, expected {:?}", actual, expected);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-9, "roots {:?}, expected {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn quadratic_roots_are_sorted() {
+        assert_eq!(solve_quadratic(1.0, -3.0, 2.0), Some((1.0, 2.0)));
+        assert_eq!(solve_quadratic(-1.0, 3.0, -2.0), Some((1.0, 2.0)));
+        assert_eq!(solve_quadratic(1.0, 0.0, 1.0), None);
+        // Degenerates to the linear case
+        assert_eq!(solve_quadratic(0.0, 2.0, -4.0), Some((2.0, 2.0)));
+    }
+
+    #[test]
+    fn quadratic_avoids_cancellation() {
+        // Roots 1e-8 and 1e8, the naive formula loses the small one entirely
+        let (t0, t1) = solve_quadratic(1.0, -(1e8 + 1e-8), 1.0).unwrap();
+        assert!((t0 - 1e-8).abs() < 1e-20 && (t1 - 1e8).abs() < 1e-4);
+    }
+
+    #[test]
+    fn real_roots_of_each_degree() {
+        assert_roots(&real_roots(&[2.0, -4.0], -10.0, 10.0), &[2.0]);
+        assert_roots(&real_roots(&[1.0, -3.0, 2.0], -10.0, 10.0), &[1.0, 2.0]);
+        // (t - 1)(t - 2)(t - 3)
+        assert_roots(&real_roots(&[1.0, -6.0, 11.0, -6.0], -10.0, 10.0), &[1.0, 2.0, 3.0]);
+        // (t - 1)(t - 2)(t - 3)(t - 4)
+        assert_roots(&real_roots(&[1.0, -10.0, 35.0, -50.0, 24.0], -10.0, 10.0), &[1.0, 2.0, 3.0, 4.0]);
+        // (t^2 - 0.25)(t^2 - 6.25), the quartic of a ray through the middle of a torus with radii 1.5 and 1
+        assert_roots(&real_roots(&[1.0, 0.0, -6.5, 0.0, 1.5625], -10.0, 10.0), &[-2.5, -0.5, 0.5, 2.5]);
+    }
+
+    #[test]
+    fn real_roots_respects_the_interval() {
+        assert_roots(&real_roots(&[1.0, -10.0, 35.0, -50.0, 24.0], 1.5, 3.5), &[2.0, 3.0]);
+        assert_roots(&real_roots(&[1.0, 0.0, 0.0, 0.0, 1.0], -10.0, 10.0), &[]);
+        // Leading zeros lower the degree
+        assert_roots(&real_roots(&[0.0, 0.0, 1.0, -3.0, 2.0], -10.0, 10.0), &[1.0, 2.0]);
+    }
+}
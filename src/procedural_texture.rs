@@ -0,0 +1,430 @@
+use crate::{Color, Texture, TextureCoord};
+use cgmath::{InnerSpace, Vector3};
+use std::sync::Arc;
+
+/// Coordinate system a procedural texture is evaluated in.
+#[derive(Clone, Copy)]
+pub enum TextureSpace {
+    /// Local space of the node, the pattern sticks to the object when it moves.
+    Object,
+    World,
+    /// Surface UVs as `(u, v, 0)`.
+    Uv,
+}
+
+fn lookup(space: TextureSpace, scale: f64, coord: &TextureCoord) -> Vector3<f64> {
+    let p = match space {
+        TextureSpace::Object => Vector3::new(coord.object_point.x, coord.object_point.y, coord.object_point.z),
+        TextureSpace::World => Vector3::new(coord.world_point.x, coord.world_point.y, coord.world_point.z),
+        TextureSpace::Uv => Vector3::new(coord.uv.x, coord.uv.y, 0.0),
+    };
+    p * scale
+}
+
+/// Piecewise linear mapping from `[0, 1]` to colors.
+pub struct ColorRamp {
+    stops: Vec<(f32, Color)>,
+}
+
+impl ColorRamp {
+    /// Stops are sorted by position, values outside their range take the color of the nearest stop.
+    pub fn new(mut stops: Vec<(f32, Color)>) -> ColorRamp {
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        ColorRamp {
+            stops: stops,
+        }
+    }
+
+    pub fn two(from: Color, to: Color) -> ColorRamp {
+        ColorRamp::new(vec![(0.0, from), (1.0, to)])
+    }
+
+    pub fn evaluate(&self, t: f32) -> Color {
+        let first = match self.stops.first() {
+            Some(stop) => stop,
+            None => return Color::new(0.0, 0.0, 0.0),
+        };
+        if t <= first.0 {
+            return first.1.copy();
+        }
+        for pair in self.stops.windows(2) {
+            let (t0, ref c0) = pair[0];
+            let (t1, ref c1) = pair[1];
+            if t <= t1 {
+                let f = if t1 > t0 { (t - t0) / (t1 - t0) } else { 1.0 };
+                return c0.copy() * (1.0 - f) + c1.copy() * f;
+            }
+        }
+        return self.stops[self.stops.len() - 1].1.copy();
+    }
+}
+
+//------------------------------- Noise functions ----------------------------------
+
+// Ken Perlin's reference permutation
+const PERMUTATION: [u8; 256] = [
+    151, 160, 137, 91, 90, 15, 131, 13, 201, 95, 96, 53, 194, 233, 7, 225, 140, 36, 103, 30, 69, 142,
+    8, 99, 37, 240, 21, 10, 23, 190, 6, 148, 247, 120, 234, 75, 0, 26, 197, 62, 94, 252, 219, 203, 117,
+    35, 11, 32, 57, 177, 33, 88, 237, 149, 56, 87, 174, 20, 125, 136, 171, 168, 68, 175, 74, 165, 71,
+    134, 139, 48, 27, 166, 77, 146, 158, 231, 83, 111, 229, 122, 60, 211, 133, 230, 220, 105, 92, 41,
+    55, 46, 245, 40, 244, 102, 143, 54, 65, 25, 63, 161, 1, 216, 80, 73, 209, 76, 132, 187, 208, 89,
+    18, 169, 200, 196, 135, 130, 116, 188, 159, 86, 164, 100, 109, 198, 173, 186, 3, 64, 52, 217, 226,
+    250, 124, 123, 5, 202, 38, 147, 118, 126, 255, 82, 85, 212, 207, 206, 59, 227, 47, 16, 58, 17, 182,
+    189, 28, 42, 223, 183, 170, 213, 119, 248, 152, 2, 44, 154, 163, 70, 221, 153, 101, 155, 167, 43,
+    172, 9, 129, 22, 39, 253, 19, 98, 108, 110, 79, 113, 224, 232, 178, 185, 112, 104, 218, 246, 97,
+    228, 251, 34, 242, 193, 238, 210, 144, 12, 191, 179, 162, 241, 81, 51, 145, 235, 249, 14, 239,
+    107, 49, 192, 214, 31, 181, 199, 106, 157, 184, 84, 204, 176, 115, 121, 50, 45, 127, 4, 150, 254,
+    138, 236, 205, 93, 222, 114, 67, 29, 24, 72, 243, 141, 128, 195, 78, 66, 215, 61, 156, 180,
+];
+
+fn perm(i: i64) -> i64 {
+    PERMUTATION[(i & 255) as usize] as i64
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+fn grad(hash: i64, x: f64, y: f64, z: f64) -> f64 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+/// Improved Perlin gradient noise, roughly in `[-1, 1]`.
+pub fn perlin(p: Vector3<f64>) -> f64 {
+    let (xf, yf, zf) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (xi, yi, zi) = (xf as i64, yf as i64, zf as i64);
+    let (x, y, z) = (p.x - xf, p.y - yf, p.z - zf);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let a = perm(xi) + yi;
+    let aa = perm(a) + zi;
+    let ab = perm(a + 1) + zi;
+    let b = perm(xi + 1) + yi;
+    let ba = perm(b) + zi;
+    let bb = perm(b + 1) + zi;
+
+    lerp(w, lerp(v, lerp(u, grad(perm(aa), x, y, z), grad(perm(ba), x - 1.0, y, z)),
+                    lerp(u, grad(perm(ab), x, y - 1.0, z), grad(perm(bb), x - 1.0, y - 1.0, z))),
+            lerp(v, lerp(u, grad(perm(aa + 1), x, y, z - 1.0), grad(perm(ba + 1), x - 1.0, y, z - 1.0)),
+                    lerp(u, grad(perm(ab + 1), x, y - 1.0, z - 1.0), grad(perm(bb + 1), x - 1.0, y - 1.0, z - 1.0))))
+}
+
+/// Fractional brownian motion, a sum of `octaves` noise layers with rising frequency and falling amplitude.
+pub fn fbm(p: Vector3<f64>, octaves: u32, lacunarity: f64, gain: f64) -> f64 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    for _ in 0..octaves {
+        sum += amplitude * perlin(p * frequency);
+        amplitude *= gain;
+        frequency *= lacunarity;
+    }
+    sum
+}
+
+/// Like `fbm` but summing absolute values, which gives the creases typical for fire and marble.
+pub fn turbulence(p: Vector3<f64>, octaves: u32, lacunarity: f64, gain: f64) -> f64 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    for _ in 0..octaves {
+        sum += amplitude * perlin(p * frequency).abs();
+        amplitude *= gain;
+        frequency *= lacunarity;
+    }
+    sum
+}
+
+fn cell_hash(x: i64, y: i64, z: i64, seed: i64) -> f64 {
+    let h = perm(perm(perm(x + seed) + y) + z);
+    (h as f64 + 0.5) / 256.0
+}
+
+/// Distance to the nearest feature point of a jittered grid with one point per unit cell (Worley F1).
+pub fn worley(p: Vector3<f64>) -> f64 {
+    let (xi, yi, zi) = (p.x.floor() as i64, p.y.floor() as i64, p.z.floor() as i64);
+    let mut nearest = f64::MAX;
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            for dz in -1..=1 {
+                let (cx, cy, cz) = (xi + dx, yi + dy, zi + dz);
+                let feature = Vector3::new(
+                    cx as f64 + cell_hash(cx, cy, cz, 0),
+                    cy as f64 + cell_hash(cx, cy, cz, 71),
+                    cz as f64 + cell_hash(cx, cy, cz, 157),
+                );
+                nearest = nearest.min((feature - p).magnitude());
+            }
+        }
+    }
+    nearest
+}
+
+//------------------------------- Textures -----------------------------------------
+
+/// Alternating 3D cells of two textures, each cell `1 / scale` units wide.
+pub struct CheckerTexture {
+    pub space: TextureSpace,
+    pub scale: f64,
+    pub even: Arc<dyn Texture>,
+    pub odd: Arc<dyn Texture>,
+}
+
+impl CheckerTexture {
+    pub fn new(space: TextureSpace, scale: f64, even: Arc<dyn Texture>, odd: Arc<dyn Texture>) -> CheckerTexture {
+        CheckerTexture {
+            space: space,
+            scale: scale,
+            even: even,
+            odd: odd,
+        }
+    }
+}
+
+impl Texture for CheckerTexture {
+    fn sample(&self, coord: &TextureCoord) -> Color {
+        let p = lookup(self.space, self.scale, coord);
+        let sum = p.x.floor() as i64 + p.y.floor() as i64 + p.z.floor() as i64;
+        if sum.rem_euclid(2) == 0 { self.even.sample(coord) } else { self.odd.sample(coord) }
+    }
+}
+
+/// Lines of `line_width` (relative to a cell) where the surface crosses the lattice planes, over a fill texture.
+pub struct GridTexture {
+    pub space: TextureSpace,
+    pub scale: f64,
+    pub line_width: f64,
+    pub line: Arc<dyn Texture>,
+    pub fill: Arc<dyn Texture>,
+}
+
+impl GridTexture {
+    pub fn new(space: TextureSpace, scale: f64, line_width: f64, line: Arc<dyn Texture>, fill: Arc<dyn Texture>) -> GridTexture {
+        GridTexture {
+            space: space,
+            scale: scale,
+            line_width: line_width,
+            line: line,
+            fill: fill,
+        }
+    }
+}
+
+impl Texture for GridTexture {
+    fn sample(&self, coord: &TextureCoord) -> Color {
+        let p = lookup(self.space, self.scale, coord);
+        let half = self.line_width * 0.5;
+        let on_line = |x: f64| {
+            let f = x - x.floor();
+            f < half || f > 1.0 - half
+        };
+        // UV space is flat, its constant z coordinate must not count as a line
+        let on_z = match self.space {
+            TextureSpace::Uv => false,
+            _ => on_line(p.z),
+        };
+        if on_line(p.x) || on_line(p.y) || on_z { self.line.sample(coord) } else { self.fill.sample(coord) }
+    }
+}
+
+/// Linear ramp along `direction`, repeating every `1 / scale` units.
+pub struct GradientTexture {
+    pub space: TextureSpace,
+    pub scale: f64,
+    pub direction: Vector3<f64>,
+    pub ramp: ColorRamp,
+}
+
+impl GradientTexture {
+    pub fn new(space: TextureSpace, scale: f64, direction: Vector3<f64>, ramp: ColorRamp) -> GradientTexture {
+        GradientTexture {
+            space: space,
+            scale: scale,
+            direction: direction.normalize(),
+            ramp: ramp,
+        }
+    }
+}
+
+impl Texture for GradientTexture {
+    fn sample(&self, coord: &TextureCoord) -> Color {
+        let t = lookup(self.space, self.scale, coord).dot(self.direction);
+        self.ramp.evaluate((t - t.floor()) as f32)
+    }
+}
+
+pub enum NoiseKind {
+    Fbm,
+    Turbulence,
+}
+
+/// Perlin noise layered by `fbm` or `turbulence` and mapped through a color ramp.
+pub struct NoiseTexture {
+    pub space: TextureSpace,
+    pub scale: f64,
+    pub kind: NoiseKind,
+    pub octaves: u32,
+    pub lacunarity: f64,
+    pub gain: f64,
+    pub ramp: ColorRamp,
+}
+
+impl NoiseTexture {
+    pub fn new(space: TextureSpace, scale: f64, kind: NoiseKind, ramp: ColorRamp) -> NoiseTexture {
+        NoiseTexture {
+            space: space,
+            scale: scale,
+            kind: kind,
+            octaves: 5,
+            lacunarity: 2.0,
+            gain: 0.5,
+            ramp: ramp,
+        }
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn sample(&self, coord: &TextureCoord) -> Color {
+        let p = lookup(self.space, self.scale, coord);
+        let t = match self.kind {
+            NoiseKind::Fbm => 0.5 + 0.5 * fbm(p, self.octaves, self.lacunarity, self.gain),
+            NoiseKind::Turbulence => turbulence(p, self.octaves, self.lacunarity, self.gain),
+        };
+        self.ramp.evaluate(t as f32)
+    }
+}
+
+/// Cellular pattern from the distance to the nearest Worley feature point.
+pub struct WorleyTexture {
+    pub space: TextureSpace,
+    pub scale: f64,
+    pub ramp: ColorRamp,
+}
+
+impl WorleyTexture {
+    pub fn new(space: TextureSpace, scale: f64, ramp: ColorRamp) -> WorleyTexture {
+        WorleyTexture {
+            space: space,
+            scale: scale,
+            ramp: ramp,
+        }
+    }
+}
+
+impl Texture for WorleyTexture {
+    fn sample(&self, coord: &TextureCoord) -> Color {
+        let p = lookup(self.space, self.scale, coord);
+        self.ramp.evaluate(worley(p) as f32)
+    }
+}
+
+/// Veins from a sine along x, distorted by turbulence.
+pub struct MarbleTexture {
+    pub space: TextureSpace,
+    pub scale: f64,
+    /// Strength of the distortion, 0 gives straight stripes.
+    pub turbulence: f64,
+    pub octaves: u32,
+    pub ramp: ColorRamp,
+}
+
+impl MarbleTexture {
+    pub fn new(space: TextureSpace, scale: f64, turbulence: f64, ramp: ColorRamp) -> MarbleTexture {
+        MarbleTexture {
+            space: space,
+            scale: scale,
+            turbulence: turbulence,
+            octaves: 6,
+            ramp: ramp,
+        }
+    }
+}
+
+impl Texture for MarbleTexture {
+    fn sample(&self, coord: &TextureCoord) -> Color {
+        let p = lookup(self.space, self.scale, coord);
+        let t = (p.x + self.turbulence * turbulence(p, self.octaves, 2.0, 0.5)).sin();
+        self.ramp.evaluate((0.5 + 0.5 * t) as f32)
+    }
+}
+
+/// Concentric rings around the y axis with noise wobble, `rings` per unit of radius.
+pub struct WoodTexture {
+    pub space: TextureSpace,
+    pub scale: f64,
+    pub rings: f64,
+    pub turbulence: f64,
+    pub ramp: ColorRamp,
+}
+
+impl WoodTexture {
+    pub fn new(space: TextureSpace, scale: f64, rings: f64, turbulence: f64, ramp: ColorRamp) -> WoodTexture {
+        WoodTexture {
+            space: space,
+            scale: scale,
+            rings: rings,
+            turbulence: turbulence,
+            ramp: ramp,
+        }
+    }
+}
+
+impl Texture for WoodTexture {
+    fn sample(&self, coord: &TextureCoord) -> Color {
+        let p = lookup(self.space, self.scale, coord);
+        let radius = (p.x * p.x + p.z * p.z).sqrt() + self.turbulence * fbm(p, 3, 2.0, 0.5);
+        let t = radius * self.rings;
+        self.ramp.evaluate((t - t.floor()) as f32)
+    }
+}
+
+/// Blends two textures by the scalar value of a third one.
+pub struct MixTexture {
+    pub a: Arc<dyn Texture>,
+    pub b: Arc<dyn Texture>,
+    pub factor: Arc<dyn Texture>,
+}
+
+impl MixTexture {
+    pub fn new(a: Arc<dyn Texture>, b: Arc<dyn Texture>, factor: Arc<dyn Texture>) -> MixTexture {
+        MixTexture {
+            a: a,
+            b: b,
+            factor: factor,
+        }
+    }
+}
+
+impl Texture for MixTexture {
+    fn sample(&self, coord: &TextureCoord) -> Color {
+        let f = self.factor.sample_float(coord).clamp(0.0, 1.0);
+        self.a.sample(coord) * (1.0 - f) + self.b.sample(coord) * f
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_ramp_sorts_and_interpolates() {
+        let ramp = ColorRamp::new(vec![(1.0, Color::new(1.0, 1.0, 1.0)), (0.0, Color::new(0.0, 0.0, 0.0))]);
+        assert_eq!(ramp.evaluate(-1.0).red, 0.0);
+        assert!((ramp.evaluate(0.25).green - 0.25).abs() < 1e-6);
+        assert_eq!(ramp.evaluate(2.0).blue, 1.0);
+    }
+
+    #[test]
+    fn color_ramp_accepts_nan_stops() {
+        let ramp = ColorRamp::new(vec![(f32::NAN, Color::new(1.0, 0.0, 0.0)), (0.0, Color::new(0.0, 0.0, 0.0)),
+                                       (1.0, Color::new(0.0, 0.0, 1.0))]);
+        assert!((ramp.evaluate(0.5).blue - 0.5).abs() < 1e-6);
+    }
+}
@@ -1,156 +1,440 @@
-use crate::{Scene, CameraNode, Node, Ray, Color, DirectionalLight};
-use std::rc::{Rc};
-use std::cell::RefCell;
-use image::{DynamicImage, GenericImage, Pixel, Rgba, ImageFormat};
+use crate::{Scene, CompiledCamera, Ray, Color, Aov, AovBuffer, AovSample, TextureCoord, HitRecord};
+use crate::aov::{aov_path, id_from_name, AovFilter};
+use crate::compiled_scene::{CompiledScene, Primitive};
+use crate::Rng;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use image::{DynamicImage, GenericImage, ImageFormat, Rgba};
 use std::fs::{OpenOptions};
+use std::io;
 use std::f64;
-use cgmath::{InnerSpace, Vector3};
-use std::time::{Duration, Instant};
+use std::f64::consts::PI;
+use cgmath::{InnerSpace, Vector2, Vector3, Point3, Transform};
+
+/// Relative amount shadow rays stop short of their light, so the light itself never counts as an occluder.
+const SHADOW_EPSILON: f64 = 1e-4;
+
+/// Output color of a pixel and its values in every requested pass.
+type RenderedPixel = (Rgba<u8>, Vec<[f32; 3]>);
+
+/// How the alpha channel is applied to the color channels of the output image.
+pub enum AlphaMode {
+    /// Color channels are stored independently of coverage (unassociated alpha).
+    Straight,
+    /// Color channels are already multiplied by coverage (associated alpha).
+    Premultiplied,
+}
 
 pub struct RenderSystem {
     pub output_path: String,
+    /// Subsamples per pixel along each axis, so every pixel is sampled `samples * samples` times.
+    pub samples: u32,
+    /// Leave pixels that hit no geometry fully transparent instead of filling them with `background`.
+    pub transparent_background: bool,
+    pub background: Color,
+    pub alpha_mode: AlphaMode,
+    /// Passes written next to the beauty image, see `aov_path` for their file names.
+    pub aovs: Vec<Aov>,
+    /// Name of the camera to render through, the scene's active camera if `None`.
+    pub camera: Option<String>,
 }
 
 impl RenderSystem {
-    pub fn render(self, scene: &Scene, camera: Rc<RefCell<CameraNode>>) {
-        let width = value!(camera).image_width as u32;
-        let height = value!(camera).image_height as u32;
-        let mut image = DynamicImage::new_rgb8(width, height);
+    pub fn new(output_path: String) -> RenderSystem {
+        RenderSystem {
+            output_path: output_path,
+            samples: 2,
+            transparent_background: false,
+            background: Color::new_rgb(135, 206, 255),
+            alpha_mode: AlphaMode::Straight,
+            aovs: vec![],
+            camera: None,
+        }
+    }
+
+    /// Renders the scene through the requested camera and writes the image and passes.
+    ///
+    /// Fails with `NotFound` before tracing any ray if the scene has no camera of that name, or no camera at all
+    /// when none is requested, or if a lens focuses on a node that is not in the scene.
+    pub fn render(&self, scene: &Scene) -> io::Result<()> {
+        let compiled = CompiledScene::new(scene)?;
+        let camera = match compiled.camera(self.camera.as_deref()) {
+            Some(camera) => camera,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!(
+                "scene {} has no camera {}", scene.name, self.camera.as_deref().unwrap_or("to render")))),
+        };
+        let (width, height) = camera.output_size();
+        let mut image = DynamicImage::new_rgba8(width, height);
+        let mut buffers: Vec<AovBuffer> = self.aovs.iter().map(|aov| AovBuffer::new(*aov, width, height)).collect();
 
         println!("Render Loop");
-        for x in 0..width {
-            for y in 0..height {
-                //let now = Instant::now();
-                let ray = Ray::create_prime(x, y, width, height);
-                let back = Rgba::from_channels(135, 206, 255, 0);
-
-                //****************** IMPORTANT ********************************
-                //Only works with the current implemented scene in main.rs*****
-                //println!("Pic: {} {}", x, y);
-                
-                // if value!(sphere).intersect(&ray) {
-                //     image.put_pixel(x, y, Rgba::from_channels(0, 255, 0, 0));
-                // }
-                let mut intersect_light = false;
-
-                for light in &scene.lights {
-                    if value!(light).intersect(&ray) {
-                        let c = value!(light).color();
-                        image.put_pixel(x, y, Rgba::from_channels(c.red(), c.green(), c.blue(), 0));
-                        intersect_light = true;
-                        continue;
+        // Rows are handed out one at a time to as many threads as the machine runs in parallel
+        let next_row = AtomicUsize::new(0);
+        let rows: Mutex<Vec<(u32, Vec<RenderedPixel>)>> = Mutex::new(vec![]);
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| loop {
+                    let y = next_row.fetch_add(1, Ordering::Relaxed) as u32;
+                    if y >= height {
+                        break;
                     }
-                }
+                    let row = (0..width).map(|x| self.render_pixel(&compiled, camera, x, y)).collect();
+                    rows.lock().unwrap().push((y, row));
+                });
+            }
+        });
 
-                if intersect_light {
-                    continue;
+        for (y, row) in rows.into_inner().unwrap() {
+            for (x, (color, aov_values)) in row.into_iter().enumerate() {
+                image.put_pixel(x as u32, y, color);
+                for (i, buffer) in buffers.iter_mut().enumerate() {
+                    buffer.set(x as u32, y, aov_values[i]);
                 }
-
-                let root = Rc::clone(&scene.root);
-                match RenderSystem::get_intersection_obj(&ray, root) {
-                    None => image.put_pixel(x, y, back),
-                    Some(a) => {
-                        let c = RenderSystem::get_color(&scene, &ray, &a);
-                        image.put_pixel(x, y, Rgba::from_channels(c.red(), c.green(), c.blue(), 0))
-                    },
-                };
-                //let dur = now.elapsed();
-                //println!("Find Time: {}.{}.{} sek.", dur.as_secs(), dur.subsec_millis(), dur.subsec_micros());
             }
         }
 
         println!("Render Pic: {} {}!", width, height);
-        let mut image_file = OpenOptions::new().write(true).truncate(true).create(true).open(self.output_path).unwrap();
-        image.save(&mut image_file, ImageFormat::PNG).unwrap();
+        let mut image_file = OpenOptions::new().write(true).truncate(true).create(true).open(&self.output_path)?;
+        image.save(&mut image_file, ImageFormat::PNG).map_err(|e| io::Error::other(e.to_string()))?;
+        for buffer in &buffers {
+            buffer.write_pfm(&aov_path(&self.output_path, buffer.aov))?;
+        }
+        Ok(())
     }
 
-    fn get_intersection_obj(ray: &Ray, node: Rc<RefCell<Node>>) -> Option<IntersectionObject> {
-        let mut cur_obj: Option<IntersectionObject> = None;
-        let mut cur_distance: f64 = f64::MAX;
-        let size = value!(node).get_size();
-        
-        for i in 0..size {
-            //Unsafe
-            let child = match value!(node).get_child(i) {
-                None => continue,
-                Some(x) => x,
-            };
+    /// Final color of a pixel and the values of the requested passes.
+    fn render_pixel(&self, compiled: &CompiledScene, camera: &CompiledCamera, x: u32, y: u32) -> RenderedPixel {
+        let samples = self.samples.max(1);
+        let sample_count = (samples * samples) as f32;
 
-            match RenderSystem::get_intersection_obj(&ray, child) {
-                None => (),
-                Some(x) => {
-                    //println!("I=0");
-                    if cur_obj.is_some() {
-                        if cur_distance != f64::MAX {
+        // Accumulate premultiplied color and geometry coverage over a regular subsample grid
+        let mut color = Color::new(0.0, 0.0, 0.0);
+        let mut coverage = 0.0f32;
+        let mut aov_values = vec![[0.0f32; 3]; self.aovs.len()];
+        let mut rng = Rng::for_pixel(x, y);
+        for sx in 0..samples {
+            for sy in 0..samples {
+                let px = x as f64 + (sx as f64 + 0.5) / samples as f64;
+                let py = y as f64 + (sy as f64 + 0.5) / samples as f64;
+                let ray = camera.generate_ray(px, py, &mut rng);
+                if let Some(sample) = ray.and_then(|ray| RenderSystem::trace(compiled, &ray, &mut rng)) {
+                    for (i, aov) in self.aovs.iter().enumerate() {
+                        let value = sample.value(*aov);
+                        if aov.filter() != AovFilter::First {
+                            for c in 0..3 {
+                                aov_values[i][c] += value[c];
+                            }
+                        } else if coverage == 0.0 {
+                            aov_values[i] = value;
                         }
-                        if x.distance < cur_distance {
-                            cur_distance = x.distance;
-                            cur_obj = Some(x);
-                        }
-                    }
-                    else {
-                        cur_distance = x.distance;
-                        cur_obj = Some(x);
                     }
+                    color = color + sample.beauty;
+                    coverage += 1.0;
                 }
+            }
+        }
+
+        for (i, aov) in self.aovs.iter().enumerate() {
+            let weight = match aov.filter() {
+                AovFilter::Pixel => sample_count,
+                AovFilter::Covered => coverage.max(1.0),
+                AovFilter::First => 1.0,
             };
+            let value = &mut aov_values[i];
+            value.iter_mut().for_each(|c| *c /= weight);
+            // Averaged normals are shorter than one unless all samples agree
+            if *aov == Aov::Normal {
+                let length = value.iter().map(|c| c * c).sum::<f32>().sqrt();
+                if length > 0.0 {
+                    value.iter_mut().for_each(|c| *c /= length);
+                }
+            }
         }
 
-        let intersect = match value!(node).intersect(&ray) {
-            None => return cur_obj,
-            Some(x) => (x.0, x.1)
+        let mut alpha = coverage / sample_count;
+        let mut color = color * (1.0 / sample_count);
+        if !self.transparent_background {
+            color = color + self.background.copy() * (1.0 - alpha);
+            alpha = 1.0;
+        }
+
+        let color = match self.alpha_mode {
+            AlphaMode::Premultiplied => color,
+            AlphaMode::Straight if alpha > 0.0 => color * (1.0 / alpha),
+            AlphaMode::Straight => color,
         };
+        (color.clamp().to_rgba_alpha(alpha), aov_values)
+    }
+
+    /// Shades a single camera ray, returns `None` if it leaves the scene without hitting anything.
+    fn trace(compiled: &CompiledScene, ray: &Ray, rng: &mut Rng) -> Option<AovSample> {
+        let nearest = compiled.intersect(ray);
 
-        if intersect.0 < cur_distance {
-            cur_obj = Some(IntersectionObject {
-                distance: intersect.0,
-                obj: Rc::clone(&node),
-                normal: intersect.1,
+        // Spherical lights are visible to camera rays unless geometry lies in front of them
+        let mut light_ray = Ray {
+            origin: ray.origin,
+            direction: ray.direction,
+            t_min: ray.t_min,
+            t_max: nearest.as_ref().map_or(ray.t_max, |(hit, _)| hit.distance),
+            time: ray.time,
+        };
+        let mut visible_light = None;
+        for light in &compiled.lights {
+            if let Some(distance) = light.intersect(&light_ray) {
+                light_ray.t_max = distance;
+                visible_light = Some((light, distance));
+            }
+        }
+
+        if let Some((light, distance)) = visible_light {
+            let id = id_from_name(&light.name);
+            return Some(AovSample {
+                beauty: light.color(),
+                albedo: Color::new(0.0, 0.0, 0.0),
+                normal: Vector3::new(0.0, 0.0, 0.0),
+                depth: distance,
+                position: ray.origin + ray.direction * distance,
+                direct_diffuse: Color::new(0.0, 0.0, 0.0),
+                emission: light.color(),
+                shadow: Color::new(0.0, 0.0, 0.0),
+                object_id: id,
+                material_id: id,
             });
         }
-        return cur_obj;
+
+        nearest.map(|(hit, primitive)| RenderSystem::get_color(compiled, &hit, primitive, ray.time, rng))
     }
 
-    fn get_color(scene: &Scene, ray: &Ray, intersection: &IntersectionObject) -> Color {
-        let hit_point = ray.origin + (ray.direction * (intersection.distance - 0.01));
-        //let surface_normal = intersection.element.surface_normal(&hit_point);
+    /// Shades `hit`, moving geometry and shadow rays are placed at `time`.
+    fn get_color(compiled: &CompiledScene, hit: &HitRecord, primitive: &Primitive, time: f64, rng: &mut Rng)
+                 -> AovSample {
+        let (_, inverse_transform) = primitive.transforms_at(time);
+        let object_point = |p: Point3<f64>| inverse_transform.transform_point(p);
+        let coord = TextureCoord {
+            uv: hit.uv,
+            object_point: object_point(hit.position),
+            world_point: hit.position,
+        };
+        let coord_at = |du: f64, dv: f64| {
+            let p = hit.position + hit.dpdu * du + hit.dpdv * dv;
+            TextureCoord {
+                uv: hit.uv + Vector2::new(du, dv),
+                object_point: object_point(p),
+                world_point: p,
+            }
+        };
+        let (albedo, normal, emission, material_id) = match hit.material {
+            Some(ref material) => {
+                // Maps perturb the outward normal, the result is turned back towards the ray afterwards
+                let outward = if hit.front_face { hit.shading_normal } else { -hit.shading_normal };
+                let normal = material.shading_normal(&coord, outward, hit.dpdu, hit.dpdv, &coord_at);
+                let normal = if hit.front_face { normal } else { -normal };
+                (material.albedo.sample(&coord), normal, material.emitted(&coord, hit.front_face), id_from_name(&material.name))
+            }
+            None => (Color::new(0.0, 0.0, 0.0), hit.shading_normal, Color::new(0.0, 0.0, 0.0), 0),
+        };
+
         let mut power = Color::new(0.0, 0.0, 0.0);
-        for light in &scene.lights {
-            let direction_to_light = -value!(light).direction_from(&hit_point).normalize();
-            let light_power = intersection.normal.dot(direction_to_light).max(0.0) as f32 * value!(light).intensity(&hit_point);
-        
-            //println!("{}", light_power);
-            //let light_power = (light_power).max(0.0);
-            //println!("{}", light_power);
-            //let light_power = (light_power).max(0.0) * value!(scene.lights).intensity(&hit_point);
-            //
+        let mut unshadowed_power = Color::new(0.0, 0.0, 0.0);
+        for light in &compiled.lights {
+            let direction_to_light = light.direction_from(&hit.position).normalize();
+            let light_power = normal.dot(direction_to_light).max(0.0) as f32 * light.intensity(&hit.position);
             
-            let light_reflected = 1.0; /// std::f32::consts::PI;
-            //println!("{}", light_power);
+            //let light_reflected = 1.0 / std::f32::consts::PI;
 
-            let shadow_ray = Ray {
-                origin: hit_point,
-                direction: -direction_to_light,
-            };
+            // Only occluders strictly between the surface and the light cast a shadow
+            let mut shadow_ray = hit.spawn_ray(direction_to_light);
+            shadow_ray.time = time;
+            shadow_ray.t_max = light.distance(&shadow_ray.origin) * (1.0 - SHADOW_EPSILON);
 
-            let root = Rc::clone(&scene.root);
-            let shadow_intersection = RenderSystem::get_intersection_obj(&shadow_ray, root);
-            let in_light = shadow_intersection.is_none() ||
-                        shadow_intersection.unwrap().distance > value!(light).distance(&hit_point);
+            let in_light = compiled.intersect(&shadow_ray).is_none();
             
+            unshadowed_power = unshadowed_power + light.color() * light_power;
             let light_power = if in_light { light_power } else {  0.0 };
-            power = power + value!(light).color() * light_power;
+            power = power + light.color() * light_power;
+        }
+
+        for index in &compiled.emitters {
+            let (light_power, unshadowed_light_power) = RenderSystem::emitter_power(compiled, *index, hit, normal, time, rng);
+            power = power + light_power;
+            unshadowed_power = unshadowed_power + unshadowed_light_power;
         }
         
-        //let light_power = (surface_normal.dot(&direction_to_light) as f32).max(0.0) * light_intensity;
-        let color = value!(intersection.obj).get_color().copy() * power; //* light_reflected;
-        //let color = value!(intersection.obj).get_color().copy();
-        color.clamp()
+        let color = (albedo.copy() * power).clamp(); //* light_reflected;
+        // Light lost to occluders, i.e. what the surface would receive without shadows minus what it gets
+        let shadow = (albedo.copy() * unshadowed_power).clamp() - color.copy();
+        AovSample {
+            beauty: (color.copy() + emission.copy()).clamp(),
+            albedo: albedo.copy(),
+            normal: normal,
+            depth: hit.distance,
+            position: hit.position,
+            direct_diffuse: color,
+            emission: emission,
+            shadow: shadow,
+            object_id: primitive.object_id,
+            material_id: material_id,
+        }
+    }
+
+    /// Light arriving at `hit` from the emissive primitive `index`, with and without occlusion.
+    ///
+    /// Points are sampled on the emitter by area, the Lambertian 1/pi is folded in here as the point lights
+    /// already carry their own falloff.
+    fn emitter_power(compiled: &CompiledScene, index: usize, hit: &HitRecord, normal: Vector3<f64>, time: f64,
+                     rng: &mut Rng) -> (Color, Color) {
+        let primitive = &compiled.geometry.primitives[index];
+        let (world_transform, _) = primitive.transforms_at(time);
+        let (material, emission) = match primitive.material {
+            Some(ref material) => match material.emission {
+                Some(ref emission) => (material, emission),
+                None => return (Color::new(0.0, 0.0, 0.0), Color::new(0.0, 0.0, 0.0)),
+            },
+            None => return (Color::new(0.0, 0.0, 0.0), Color::new(0.0, 0.0, 0.0)),
+        };
+        let samples = material.light_samples.max(1);
+        let mut power = Color::new(0.0, 0.0, 0.0);
+        let mut unshadowed_power = Color::new(0.0, 0.0, 0.0);
+        for _ in 0..samples {
+            let sample = match primitive.shape.sample(rng.next_2d()) {
+                Some(sample) => sample,
+                None => continue,
+            };
+            let position = world_transform.transform_point(sample.position);
+            let area_normal = world_transform.transform_vector(sample.dpdu)
+                .cross(world_transform.transform_vector(sample.dpdv));
+            let area = area_normal.magnitude();
+            let offset = position - hit.position;
+            let distance2 = offset.magnitude2();
+            if area <= 0.0 || distance2 <= 0.0 {
+                continue;
+            }
+            let distance = distance2.sqrt();
+            let direction = offset / distance;
+
+            let cos_surface = normal.dot(direction);
+            let cos_light = -area_normal.dot(direction) / area;
+            let cos_light = if material.two_sided { cos_light.abs() } else { cos_light };
+            if cos_surface <= 0.0 || cos_light <= 0.0 {
+                continue;
+            }
+
+            let coord = TextureCoord {
+                uv: sample.uv,
+                object_point: sample.position,
+                world_point: position,
+            };
+            let contribution = emission.sample(&coord) * (cos_surface * cos_light * area / distance2) as f32;
+            unshadowed_power = unshadowed_power + contribution.copy();
+            let mut shadow_ray = hit.spawn_ray(direction);
+            shadow_ray.time = time;
+            shadow_ray.t_max = (position - shadow_ray.origin).magnitude() * (1.0 - SHADOW_EPSILON);
+            if compiled.intersect(&shadow_ray).is_none() {
+                power = power + contribution;
+            }
+        }
+        let scale = (1.0 / (PI * samples as f64)) as f32;
+        (power * scale, unshadowed_power * scale)
     }
 }
 
-struct IntersectionObject {
-    pub distance: f64,
-    pub obj: Rc<RefCell<Node>>,
-    pub normal: Vector3<f64>,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Node, Node3D, CameraNode, Plane, Material, SphereNode};
+    use crate::procedural_texture::{GradientTexture, TextureSpace, ColorRamp};
+    use crate::node::add_child;
+    use cgmath::{Matrix4, SquareMatrix, Deg};
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::sync::Arc;
+
+    /// Single pixel view whose left half is covered by a white emitting rectangle.
+    fn half_covered_scene() -> Scene {
+        let root: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new("root".to_string(), Matrix4::identity())));
+        add_child(&root, Rc::new(RefCell::new(CameraNode::new("camera".to_string(), Matrix4::identity(),
+                                                               Vector3::new(0.0, 0.0, -1.0), 1, 1))));
+        // Faces the camera and ends at x = 0
+        let transform = Matrix4::from_translation(Vector3::new(-50.0, 0.0, -5.0)) * Matrix4::from_angle_x(Deg(90.0));
+        let mut wall = Plane::rectangle("Wall".to_string(), transform, 100.0, 100.0, Color::new(0.0, 0.0, 0.0));
+        wall.material = Arc::new(Material::emissive(Color::new(1.0, 1.0, 1.0), 1.0));
+        add_child(&root, Rc::new(RefCell::new(wall)));
+        Scene::new("scene".to_string(), root)
+    }
+
+    #[test]
+    fn coverage_becomes_alpha() {
+        let compiled = CompiledScene::new(&half_covered_scene()).unwrap();
+        let camera = compiled.camera(None).unwrap();
+        let mut renderer = RenderSystem::new("unused.png".to_string());
+        renderer.transparent_background = true;
+        assert_eq!(renderer.render_pixel(&compiled, camera, 0, 0).0.data, [255, 255, 255, 127]);
+        renderer.alpha_mode = AlphaMode::Premultiplied;
+        assert_eq!(renderer.render_pixel(&compiled, camera, 0, 0).0.data, [127, 127, 127, 127]);
+        // Opaque output blends the uncovered half with the background
+        renderer.transparent_background = false;
+        let [red, _, _, alpha] = renderer.render_pixel(&compiled, camera, 0, 0).0.data;
+        assert!((194..=195).contains(&red) && alpha == 255, "red {} alpha {}", red, alpha);
+    }
+
+    #[test]
+    fn geometric_passes_ignore_misses() {
+        let compiled = CompiledScene::new(&half_covered_scene()).unwrap();
+        let camera = compiled.camera(None).unwrap();
+        let mut renderer = RenderSystem::new("unused.png".to_string());
+        renderer.aovs = vec![Aov::Depth, Aov::Normal, Aov::Emission, Aov::ObjectId];
+        let (_, values) = renderer.render_pixel(&compiled, camera, 0, 0);
+        // Both covered samples sit a quarter pixel off the center in x and y
+        let offset = (22.5f64).to_radians().tan() * 0.5;
+        let distance = 5.0 * (1.0 + 2.0 * offset * offset).sqrt();
+        assert!((values[0][0] as f64 - distance).abs() < 1e-4, "depth {:?}", values[0]);
+        assert!(values[1][0].abs() < 1e-6 && values[1][1].abs() < 1e-6 && values[1][2] == 1.0, "normal {:?}", values[1]);
+        // Color passes still fade out with coverage
+        assert_eq!(values[2], [0.5, 0.5, 0.5]);
+        assert_eq!(values[3][0] as u32, compiled.geometry.primitives[0].object_id);
+    }
+
+    #[test]
+    fn missing_camera_fails_before_writing() {
+        let root: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new("root".to_string(), Matrix4::identity())));
+        let scene = Scene::new("scene".to_string(), root);
+        let path = std::env::temp_dir().join(format!("missing-camera-{}.png", std::process::id()));
+        let mut renderer = RenderSystem::new(path.to_str().unwrap().to_string());
+        renderer.camera = Some("missing".to_string());
+        assert_eq!(renderer.render(&scene).unwrap_err().kind(), io::ErrorKind::NotFound);
+        // Without any camera in the graph the default request fails as well
+        renderer.camera = None;
+        assert_eq!(renderer.render(&scene).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn back_faces_are_bumped_like_front_faces() {
+        let root: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new("root".to_string(), Matrix4::identity())));
+        let mut sphere = SphereNode::new("Sphere".to_string(), Matrix4::identity(), 2.0, Color::new(1.0, 1.0, 1.0));
+        let mut material = Material::from_color(Color::new(1.0, 1.0, 1.0));
+        material.bump_map = Some(Arc::new(GradientTexture::new(TextureSpace::Uv, 0.5, Vector3::new(1.0, 0.0, 0.0),
+            ColorRamp::two(Color::new(0.0, 0.0, 0.0), Color::new(1.0, 1.0, 1.0)))));
+        material.bump_scale = 1.0;
+        sphere.material = Arc::new(material);
+        add_child(&root, Rc::new(RefCell::new(sphere)));
+        let compiled = CompiledScene::new(&Scene::new("scene".to_string(), root)).unwrap();
+
+        // Seen from inside, the shading normal must be the outward bumped normal turned around
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.3, 0.2).normalize());
+        let (hit, primitive) = compiled.intersect(&ray).unwrap();
+        assert!(!hit.front_face);
+        let coord_at = |du: f64, dv: f64| TextureCoord {
+            uv: hit.uv + Vector2::new(du, dv),
+            object_point: hit.position,
+            world_point: hit.position,
+        };
+        let material = hit.material.as_ref().unwrap();
+        let outward = material.shading_normal(&coord_at(0.0, 0.0), -hit.shading_normal, hit.dpdu, hit.dpdv, &coord_at);
+        let sample = RenderSystem::get_color(&compiled, &hit, primitive, 0.0, &mut Rng::new(0));
+        assert!((sample.normal + outward).magnitude() < 1e-9, "{:?} != {:?}", sample.normal, -outward);
+        assert!((outward + hit.shading_normal).magnitude() > 1e-3, "the bump map did not tilt the normal");
+    }
 }
@@ -0,0 +1,44 @@
+use cgmath::Vector2;
+
+/// Small SplitMix64 random number generator for the sampling decisions of the renderer.
+///
+/// Every pixel is seeded from its coordinates, so renders are reproducible no matter how the rows are spread
+/// over threads.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Generator for `seed`, nearby seeds give unrelated sequences.
+    pub fn new(seed: u64) -> Rng {
+        Rng {
+            state: mix(seed),
+        }
+    }
+
+    pub fn for_pixel(x: u32, y: u32) -> Rng {
+        Rng::new((y as u64) << 32 | x as u64)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        mix(self.state)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in the unit square.
+    pub fn next_2d(&mut self) -> Vector2<f64> {
+        Vector2::new(self.next_f64(), self.next_f64())
+    }
+}
+
+/// SplitMix64 output function, scrambles every bit of `z` into every bit of the result.
+fn mix(z: u64) -> u64 {
+    let z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -1,9 +1,11 @@
 extern crate image;
 extern crate cgmath;
 
-use crate::{CameraNode, Node, RenderSystem, DirectionalLight, SphericalLight, Light};
+use crate::Node;
+use crate::node;
 use std::rc::{Rc};
 use std::cell::RefCell;
+use std::io;
 
 //const viewing_direction: Vector3<f64> = Vector3::new(0.0, 0.0, -1.0);     // Viewing direction (world coordinates)
 //const start_point: Vector3<f64> = Vector3::new(0.0, 0.0, -1.0);          // Center of the Image Plane (world coordinates)
@@ -11,71 +13,75 @@ use std::cell::RefCell;
 //const image_height: usize = 720; // Size of the image plane (world coordinates)
 
 pub struct Scene {
-    pub root: Rc<RefCell<Node>>,
+    pub root: Rc<RefCell<dyn Node>>,
     pub name: String,
-    //pub mainCamera: Rc<RefCell<CameraNode>>,
-    pub lights: Vec<Rc<RefCell<Light>>>,
-    pub renderer: Rc<RefCell<RenderSystem>>,
+    /// Camera rendered when a render request names none, the first camera in the graph if `None`.
+    pub active_camera: Option<String>,
 }
 
 impl Scene {
-    pub fn new(name: String, root: Rc<RefCell<Node>>, camera: Rc<RefCell<CameraNode>>) -> Scene {
+    pub fn new(name: String, root: Rc<RefCell<dyn Node>>) -> Scene {
         Scene {
             root: root,
             name: name,
-            //mainCamera: camera,
-            lights: vec![],
-            renderer: Rc::new(RefCell::new(RenderSystem {output_path: "output.png".to_string()})),
+            active_camera: None,
         }
     }
 
-    pub fn add_root(&mut self, node: Rc<RefCell<Node>>) {
+    pub fn add_root(&mut self, node: Rc<RefCell<dyn Node>>) {
         self.root = Rc::clone(&node);
     }
 
-    // pub fn add_camera(&mut self, camera: Rc<RefCell<CameraNode>>) {
-    //     self.mainCamera = Rc::clone(&camera);
-    // }
+    /// Makes the camera named `name` the default of render requests, fails with `NotFound` if the graph has no
+    /// such camera.
+    pub fn set_active_camera(&mut self, name: &str) -> io::Result<()> {
+        if !self.camera_names().iter().any(|camera| camera == name) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("scene {} has no camera {}", self.name, name)));
+        }
+        self.active_camera = Some(name.to_string());
+        return Ok(());
+    }
 
-    pub fn get_renderer(&self) -> Rc<RefCell<RenderSystem>> {
-        return Rc::clone(&self.renderer);
+    /// Names of all cameras in the graph in depth-first order.
+    pub fn camera_names(&self) -> Vec<String> {
+        let mut names = vec![];
+        self.visit(&mut |node, _| names.extend(value!(node).get_camera().map(|camera| camera.name)));
+        return names;
     }
 
-}
+    /// First node in the scene named `name`.
+    pub fn find_by_name(&self, name: &str) -> Option<Rc<RefCell<dyn Node>>> {
+        return node::find_by_name(&self.root, name);
+    }
 
-// macro_rules! impl_T {
-//     (for $($t:ty),+) => {
-//         $(
-//             impl Node for $t {
-//                 fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-//                     let strong = &self.parent.upgrade();
-//                     let strong = match strong {
-//                         Some(x) => x,
-//                         None => return None,
-//                     };
-//                     return Some(Rc::clone(&(*strong))); //Some(Rc::clone(&(*(&self.parent))));
-//                 }
+    /// Node at a slash separated path of names starting at the root, e.g. `root/Sphere root/Sphere Red`.
+    pub fn find_by_path(&self, path: &str) -> Option<Rc<RefCell<dyn Node>>> {
+        return node::find_by_path(&self.root, path);
+    }
 
-//                 fn get_child(&self, index: usize) -> Option<Rc<RefCell<Node>>> {
-//                     if index >= self.size {
-//                         return None;
-//                     }
-//                     return Some(Rc::clone(&(self.childs[index])));
-//                 }
+    /// Visits every node of the scene depth-first together with its depth below the root.
+    pub fn visit<F: FnMut(&Rc<RefCell<dyn Node>>, usize)>(&self, visitor: &mut F) {
+        node::visit(&self.root, visitor);
+    }
 
-//                 fn add_child(&mut self, node: Rc<RefCell<Node>>) {
-//                     self.size += 1;
-//                     self.childs.push(Rc::clone(&node));
-//                 }
+}
 
-//                 fn get_size(&self) -> usize {
-//                     return self.size;
-//                 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Node3D, CameraNode};
+    use crate::node::add_child;
+    use cgmath::{Matrix4, SquareMatrix, Vector3};
 
-//                 fn intersect(&self, ray: &Ray) -> bool {
-//                     return false;
-//                 }
-//             }
-//         )*
-//     }
-// }
\ No newline at end of file
+    #[test]
+    fn unknown_active_camera_is_an_error() {
+        let root: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new("root".to_string(), Matrix4::identity())));
+        add_child(&root, Rc::new(RefCell::new(CameraNode::new("main".to_string(), Matrix4::identity(),
+                                                               Vector3::new(0.0, 0.0, -1.0), 4, 4))));
+        let mut scene = Scene::new("scene".to_string(), root);
+        assert!(scene.set_active_camera("main").is_ok());
+        let error = scene.set_active_camera("missing").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(scene.active_camera.as_deref(), Some("main"));
+    }
+}
@@ -1,6 +1,6 @@
-use cgmath::{InnerSpace, Vector3, Point3};
+use cgmath::{InnerSpace, Vector3, Point3, Matrix4, Transform};
 use image::{Pixel, Rgba};
-use std::ops::{Mul, Add};
+use std::ops::{Mul, Add, Sub};
 
 pub struct Color {
     pub red: f32,
@@ -27,9 +27,9 @@ impl Color {
 
     pub fn clamp(&self) -> Color {
         Color {
-            red: self.red.min(1.0).max(0.0),
-            blue: self.blue.min(1.0).max(0.0),
-            green: self.green.min(1.0).max(0.0),
+            red: self.red.clamp(0.0, 1.0),
+            blue: self.blue.clamp(0.0, 1.0),
+            green: self.green.clamp(0.0, 1.0),
         }
     }
 
@@ -40,6 +40,13 @@ impl Color {
                         255)
     }
 
+    pub fn to_rgba_alpha(&self, alpha: f32) -> Rgba<u8> {
+        Rgba::from_channels((self.red * 255.0) as u8,
+                        (self.green * 255.0) as u8,
+                        (self.blue * 255.0) as u8,
+                        (alpha.clamp(0.0, 1.0) * 255.0) as u8)
+    }
+
     pub fn copy(&self) -> Color {
         return Color::new(self.red, self.green, self.blue);
     }
@@ -92,29 +99,61 @@ impl Add for Color {
     }
 }
 
+impl Sub for Color {
+    type Output = Color;
+    fn sub(self, other: Color) -> Color {
+        Color {
+            red: self.red - other.red,
+            blue: self.blue - other.blue,
+            green: self.green - other.green,
+        }
+    }
+}
+
 pub struct Ray {
     pub origin: Point3<f64>,
     pub direction: Vector3<f64>,
+    /// Hits closer than `t_min` or farther than `t_max` along the ray are ignored.
+    pub t_min: f64,
+    pub t_max: f64,
+    /// Moment within the camera shutter the ray travels at, moving geometry is placed at this time.
+    pub time: f64,
 }
 
 impl Ray {
-    pub fn create_prime(x: u32, y: u32, width: u32, height: u32) -> Ray {
-    let fov: f64 = 45.0;
-    let fov_adjustment = (3.1415926 * 0.5 * fov / 180.).tan(); //(fov.to_radians() / 2.0).tan();
-    let aspect_ratio = (width as f64) / (height as f64);
-    let sensor_x = ((((x as f64 + 0.5) / width as f64) * 2.0 - 1.0) * aspect_ratio) * fov_adjustment;
-    let sensor_y = (1.0 - ((y as f64 + 0.5) / height as f64) * 2.0) * fov_adjustment;
-    
-    //let sensor_x = ((x as f64 + 0.5) / width as f64) * 2.0 - 1.0;
-    //let sensor_y = 1.0 - ((y as f64 + 0.5) / height as f64) * 2.0;
-
-    Ray {
-        origin: Point3::new(0.0, 0.0, 0.0),
-        direction: Vector3 {
-                x: sensor_x,
-                y: sensor_y,
-                z: -1.0,
-            }.normalize(),
+    pub fn new(origin: Point3<f64>, direction: Vector3<f64>) -> Ray {
+        Ray {
+            origin: origin,
+            direction: direction,
+            t_min: 0.0,
+            t_max: f64::INFINITY,
+            time: 0.0,
         }
     }
+
+    pub fn contains(&self, distance: f64) -> bool {
+        distance >= self.t_min && distance <= self.t_max
+    }
+
+    /// The ray in the space of `transform`, the direction is not renormalized so distances stay comparable.
+    pub fn transform(&self, transform: &Matrix4<f64>) -> Ray {
+        Ray {
+            origin: transform.transform_point(self.origin),
+            direction: transform.transform_vector(self.direction),
+            t_min: self.t_min,
+            t_max: self.t_max,
+            time: self.time,
+        }
+    }
+
+    /// Creates a camera space primary ray through the raster position `(x, y)`, pixel centers lie at `+0.5`.
+    /// `fov` is the vertical field of view in degrees.
+    pub fn create_prime(x: f64, y: f64, width: u32, height: u32, fov: f64) -> Ray {
+    let fov_adjustment = (fov.to_radians() / 2.0).tan();
+    let aspect_ratio = (width as f64) / (height as f64);
+    let sensor_x = (((x / width as f64) * 2.0 - 1.0) * aspect_ratio) * fov_adjustment;
+    let sensor_y = (1.0 - (y / height as f64) * 2.0) * fov_adjustment;
+
+    Ray::new(Point3::new(0.0, 0.0, 0.0), Vector3::new(sensor_x, sensor_y, -1.0).normalize())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,385 @@
+use crate::{Node, Color, Ray, Material, HitRecord, Aabb, Shape};
+use crate::compiled_scene::SceneCompiler;
+use crate::node::NodeCore;
+use std::sync::Arc;
+use cgmath::{InnerSpace, Vector2, Vector3, Point3, Matrix4, EuclideanSpace};
+
+/// Upper limit of sphere tracing steps per ray.
+const MAX_STEPS: usize = 512;
+
+/// Composable signed distance field, negative inside the shape.
+#[derive(Clone)]
+pub enum Sdf {
+    Sphere { radius: f64 },
+    Box { half_extents: Vector3<f64> },
+    /// Torus in the xz plane around the y axis.
+    Torus { major_radius: f64, minor_radius: f64 },
+    Union(Box<Sdf>, Box<Sdf>),
+    Intersection(Box<Sdf>, Box<Sdf>),
+    /// The first shape minus the second.
+    Subtraction(Box<Sdf>, Box<Sdf>),
+    /// Union that blends the shapes over a distance of `k`.
+    SmoothUnion(Box<Sdf>, Box<Sdf>, f64),
+    SmoothSubtraction(Box<Sdf>, Box<Sdf>, f64),
+    /// Grows the shape by `radius`, rounding its edges.
+    Round(Box<Sdf>, f64),
+    Translate(Box<Sdf>, Vector3<f64>),
+    Scale(Box<Sdf>, f64),
+    /// Copies of the shape every `spacing`, `limit` copies to each side of the original per axis.
+    Repeat(Box<Sdf>, Vector3<f64>, Vector3<f64>),
+    /// Rotates the xz plane by `rate` radians per unit along the y axis.
+    Twist(Box<Sdf>, f64),
+}
+
+impl Sdf {
+    pub fn sphere(radius: f64) -> Sdf {
+        Sdf::Sphere { radius: radius }
+    }
+
+    /// Box with the full edge lengths `extents`, centered on the origin.
+    pub fn cuboid(extents: Vector3<f64>) -> Sdf {
+        Sdf::Box { half_extents: extents * 0.5 }
+    }
+
+    pub fn torus(major_radius: f64, minor_radius: f64) -> Sdf {
+        Sdf::Torus { major_radius: major_radius, minor_radius: minor_radius }
+    }
+
+    pub fn union(self, other: Sdf) -> Sdf {
+        Sdf::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn intersection(self, other: Sdf) -> Sdf {
+        Sdf::Intersection(Box::new(self), Box::new(other))
+    }
+
+    pub fn subtract(self, other: Sdf) -> Sdf {
+        Sdf::Subtraction(Box::new(self), Box::new(other))
+    }
+
+    pub fn smooth_union(self, other: Sdf, k: f64) -> Sdf {
+        Sdf::SmoothUnion(Box::new(self), Box::new(other), k)
+    }
+
+    pub fn smooth_subtract(self, other: Sdf, k: f64) -> Sdf {
+        Sdf::SmoothSubtraction(Box::new(self), Box::new(other), k)
+    }
+
+    pub fn round(self, radius: f64) -> Sdf {
+        Sdf::Round(Box::new(self), radius)
+    }
+
+    pub fn translate(self, offset: Vector3<f64>) -> Sdf {
+        Sdf::Translate(Box::new(self), offset)
+    }
+
+    pub fn scale(self, factor: f64) -> Sdf {
+        Sdf::Scale(Box::new(self), factor)
+    }
+
+    pub fn repeat(self, spacing: Vector3<f64>, limit: Vector3<f64>) -> Sdf {
+        Sdf::Repeat(Box::new(self), spacing, limit)
+    }
+
+    pub fn twist(self, rate: f64) -> Sdf {
+        Sdf::Twist(Box::new(self), rate)
+    }
+
+    /// Signed distance from `p` to the surface, a lower bound for the blending and twisting operators.
+    pub fn distance(&self, p: Vector3<f64>) -> f64 {
+        match *self {
+            Sdf::Sphere { radius } => p.magnitude() - radius,
+            Sdf::Box { half_extents } => {
+                let q = Vector3::new(p.x.abs() - half_extents.x, p.y.abs() - half_extents.y, p.z.abs() - half_extents.z);
+                let outside = Vector3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).magnitude();
+                outside + q.x.max(q.y).max(q.z).min(0.0)
+            }
+            Sdf::Torus { major_radius, minor_radius } => {
+                let ring = (p.x * p.x + p.z * p.z).sqrt() - major_radius;
+                (ring * ring + p.y * p.y).sqrt() - minor_radius
+            }
+            Sdf::Union(ref a, ref b) => a.distance(p).min(b.distance(p)),
+            Sdf::Intersection(ref a, ref b) => a.distance(p).max(b.distance(p)),
+            Sdf::Subtraction(ref a, ref b) => a.distance(p).max(-b.distance(p)),
+            Sdf::SmoothUnion(ref a, ref b, k) => {
+                let (d1, d2) = (a.distance(p), b.distance(p));
+                let h = (0.5 + 0.5 * (d2 - d1) / k).clamp(0.0, 1.0);
+                d2 + (d1 - d2) * h - k * h * (1.0 - h)
+            }
+            Sdf::SmoothSubtraction(ref a, ref b, k) => {
+                // Smooth maximum of the first shape and the outside of the second
+                let (d1, d2) = (a.distance(p), -b.distance(p));
+                let h = (0.5 - 0.5 * (d2 - d1) / k).clamp(0.0, 1.0);
+                d2 + (d1 - d2) * h + k * h * (1.0 - h)
+            }
+            Sdf::Round(ref a, radius) => a.distance(p) - radius,
+            Sdf::Translate(ref a, offset) => a.distance(p - offset),
+            Sdf::Scale(ref a, factor) => a.distance(p / factor) * factor,
+            Sdf::Repeat(ref a, spacing, limit) => {
+                let cell = |x: f64, s: f64, l: f64| if s > 0.0 { x - s * (x / s).round().clamp(-l, l) } else { x };
+                a.distance(Vector3::new(cell(p.x, spacing.x, limit.x), cell(p.y, spacing.y, limit.y), cell(p.z, spacing.z, limit.z)))
+            }
+            Sdf::Twist(ref a, rate) => {
+                let (s, c) = (rate * p.y).sin_cos();
+                a.distance(Vector3::new(c * p.x - s * p.z, p.y, s * p.x + c * p.z))
+            }
+        }
+    }
+
+    /// Local bounds of the shape.
+    pub fn bounds(&self) -> Aabb {
+        match *self {
+            Sdf::Sphere { radius } => Aabb::centered(Vector3::new(radius, radius, radius)),
+            Sdf::Box { half_extents } => Aabb::centered(half_extents),
+            Sdf::Torus { major_radius, minor_radius } => {
+                let outer = major_radius + minor_radius;
+                Aabb::centered(Vector3::new(outer, minor_radius, outer))
+            }
+            Sdf::Union(ref a, ref b) => a.bounds().union(&b.bounds()),
+            Sdf::Intersection(ref a, _) | Sdf::Subtraction(ref a, _) | Sdf::SmoothSubtraction(ref a, _, _) => a.bounds(),
+            Sdf::SmoothUnion(ref a, ref b, k) => expand(&a.bounds().union(&b.bounds()), k),
+            Sdf::Round(ref a, radius) => expand(&a.bounds(), radius),
+            Sdf::Translate(ref a, offset) => {
+                let bounds = a.bounds();
+                Aabb::new(bounds.min + offset, bounds.max + offset)
+            }
+            Sdf::Scale(ref a, factor) => {
+                let bounds = a.bounds();
+                Aabb::new(Point3::from_vec(bounds.min.to_vec() * factor), Point3::from_vec(bounds.max.to_vec() * factor))
+            }
+            Sdf::Repeat(ref a, spacing, limit) => {
+                let bounds = a.bounds();
+                let reach = Vector3::new(spacing.x * limit.x, spacing.y * limit.y, spacing.z * limit.z);
+                Aabb::new(bounds.min - reach, bounds.max + reach)
+            }
+            Sdf::Twist(ref a, _) => {
+                // Any rotation around y stays within the circle through the farthest corner
+                let bounds = a.bounds();
+                let radius = bounds.corners().iter().map(|c| (c.x * c.x + c.z * c.z).sqrt()).fold(0.0, f64::max);
+                Aabb::new(Point3::new(-radius, bounds.min.y, -radius), Point3::new(radius, bounds.max.y, radius))
+            }
+        }
+    }
+
+    /// Bound on how much faster than the true distance the field can change within `radius` of the y axis,
+    /// sphere tracing divides its steps by it.
+    pub fn lipschitz(&self, radius: f64) -> f64 {
+        match *self {
+            Sdf::Sphere { .. } | Sdf::Box { .. } | Sdf::Torus { .. } => 1.0,
+            Sdf::Union(ref a, ref b) | Sdf::Intersection(ref a, ref b) | Sdf::Subtraction(ref a, ref b)
+            | Sdf::SmoothUnion(ref a, ref b, _) | Sdf::SmoothSubtraction(ref a, ref b, _) => a.lipschitz(radius).max(b.lipschitz(radius)),
+            Sdf::Round(ref a, _) => a.lipschitz(radius),
+            // Moved points can lie farther from the axis of a nested twist
+            Sdf::Translate(ref a, offset) => a.lipschitz(radius + offset.magnitude()),
+            Sdf::Repeat(ref a, spacing, limit) => {
+                a.lipschitz(radius + Vector3::new(spacing.x * limit.x, spacing.y * limit.y, spacing.z * limit.z).magnitude())
+            }
+            Sdf::Scale(ref a, factor) => a.lipschitz(radius / factor),
+            Sdf::Twist(ref a, rate) => a.lipschitz(radius) * (1.0 + (rate * radius).powi(2)).sqrt(),
+        }
+    }
+}
+
+fn expand(bounds: &Aabb, amount: f64) -> Aabb {
+    let amount = Vector3::new(amount, amount, amount);
+    Aabb::new(bounds.min - amount, bounds.max + amount)
+}
+
+/// Implicit surface given by a signed distance field in local space, intersected by sphere tracing.
+///
+/// The field has no parameterization, the UVs are always zero so textures should use object or world space.
+#[derive(Clone)]
+pub struct SdfSurface {
+    pub sdf: Sdf,
+    /// Distance to the surface at which sphere tracing reports a hit.
+    pub epsilon: f64,
+}
+
+/// Scene node placing an `SdfSurface`.
+pub struct SdfNode {
+    pub core: NodeCore,
+    pub shape: Arc<SdfSurface>,
+    pub material: Arc<Material>,
+}
+
+impl SdfNode {
+    pub fn new(name: String, transform: Matrix4<f64>, sdf: Sdf, color: Color) -> SdfNode {
+        SdfNode {
+            core: NodeCore::new(name, transform),
+            shape: Arc::new(SdfSurface {
+                sdf: sdf,
+                epsilon: 1e-4,
+            }),
+            material: Arc::new(Material::from_color(color)),
+        }
+    }
+}
+
+impl SdfSurface {
+    /// Outward normal from the central differences of the field.
+    fn normal(&self, p: Vector3<f64>) -> Vector3<f64> {
+        let h = self.epsilon;
+        let axis = |offset: Vector3<f64>| self.sdf.distance(p + offset) - self.sdf.distance(p - offset);
+        let normal = Vector3::new(axis(Vector3::new(h, 0.0, 0.0)), axis(Vector3::new(0.0, h, 0.0)), axis(Vector3::new(0.0, 0.0, h)));
+        if normal.magnitude2() > 0.0 { normal } else { Vector3::new(0.0, 1.0, 0.0) }
+    }
+
+    /// All surface crossings of a local ray within its extent ordered by distance, or only the nearest one.
+    ///
+    /// Marches by the absolute distance so the trace continues through the inside after each crossing.
+    fn local_hits(&self, ray: &Ray, first_only: bool) -> Vec<HitRecord> {
+        let bounds = self.sdf.bounds();
+        let (t_enter, t_exit) = match bounds.intersect(ray) {
+            Some(range) => range,
+            None => return vec![],
+        };
+
+        let radius = bounds.corners().iter().map(|c| (c.x * c.x + c.z * c.z).sqrt()).fold(0.0, f64::max);
+        let step = 1.0 / self.sdf.lipschitz(radius);
+        let length = ray.direction.magnitude();
+        let (t_enter, t_exit) = (t_enter * length, t_exit * length);
+        let direction = ray.direction / length;
+        let origin = ray.origin.to_vec();
+
+        let mut hits = vec![];
+        let mut s = t_enter;
+        let mut inside = self.sdf.distance(origin + direction * s) < 0.0;
+        for _ in 0..MAX_STEPS {
+            if s > t_exit {
+                break;
+            }
+            let p = origin + direction * s;
+            let distance = self.sdf.distance(p);
+            if distance.abs() >= self.epsilon && (distance < 0.0) == inside {
+                s += distance.abs() * step;
+                continue;
+            }
+
+            let normal = self.normal(p);
+            let tangent = if normal.x.abs() > 0.9 { Vector3::new(0.0, 1.0, 0.0) } else { Vector3::new(1.0, 0.0, 0.0) };
+            let dpdu = normal.cross(tangent).normalize();
+            let dpdv = normal.normalize().cross(dpdu);
+            let mut hit = HitRecord::new(ray, s / length, normal, Vector2::new(0.0, 0.0), dpdu, dpdv, None);
+            // The point is only known to lie within epsilon of the surface
+            hit.error = Vector3::new(1.0, 1.0, 1.0) * (4.0 * self.epsilon);
+            if ray.contains(hit.distance) {
+                hits.push(hit);
+                if first_only {
+                    break;
+                }
+            }
+
+            // Step off the surface before marching on
+            inside = !inside;
+            let mut escape = 0;
+            while self.sdf.distance(origin + direction * s).abs() < 2.0 * self.epsilon && escape < MAX_STEPS {
+                s += self.epsilon;
+                escape += 1;
+            }
+        }
+        hits
+    }
+}
+
+impl Shape for SdfSurface {
+    fn intersect_all(&self, ray: &Ray) -> Vec<HitRecord> {
+        self.local_hits(ray, false)
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        self.local_hits(ray, true).into_iter().next()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.sdf.bounds())
+    }
+}
+
+impl Node for SdfNode {
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
+
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
+    }
+
+    fn get_shape(&self, _compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        return Some(self.shape.clone());
+    }
+
+    fn get_material(&self) -> Option<Arc<Material>> {
+        return Some(Arc::clone(&self.material));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unit spheres centered at x = 0 and x = 1.
+    fn pair() -> (Sdf, Sdf) {
+        (Sdf::sphere(1.0), Sdf::sphere(1.0).translate(Vector3::new(1.0, 0.0, 0.0)))
+    }
+
+    /// Whether each of the points inside the first sphere only, inside both, inside the second only and outside
+    /// of both lies inside `sdf`.
+    fn inside(sdf: &Sdf) -> [bool; 4] {
+        let at = |x: f64| sdf.distance(Vector3::new(x, 0.0, 0.0)) < 0.0;
+        [at(-0.8), at(0.5), at(1.8), at(5.0)]
+    }
+
+    #[test]
+    fn combinators_have_the_right_signs() {
+        let (a, b) = pair();
+        assert_eq!(inside(&a.clone().union(b.clone())), [true, true, true, false]);
+        assert_eq!(inside(&a.clone().intersection(b.clone())), [false, true, false, false]);
+        assert_eq!(inside(&a.clone().subtract(b.clone())), [true, false, false, false]);
+        assert_eq!(inside(&a.clone().smooth_union(b.clone(), 0.1)), [true, true, true, false]);
+        assert_eq!(inside(&a.smooth_subtract(b, 0.1)), [true, false, false, false]);
+    }
+
+    #[test]
+    fn smooth_subtraction_removes_the_overlap() {
+        // Inside both spheres the hard subtraction is 1 away from the carved surface
+        let p = Vector3::new(0.0, 0.0, 0.0);
+        let carved = Sdf::sphere(2.0).smooth_subtract(Sdf::sphere(1.0), 0.5);
+        assert_eq!(carved.distance(p), Sdf::sphere(2.0).subtract(Sdf::sphere(1.0)).distance(p));
+        assert!(carved.distance(p) > 0.0);
+    }
+
+    #[test]
+    fn smooth_operators_approach_the_hard_ones() {
+        let (a, b) = pair();
+        let hard_union = a.clone().union(b.clone());
+        let hard_subtraction = a.clone().subtract(b.clone());
+        for i in 0..=40 {
+            let p = Vector3::new(-2.0 + i as f64 * 0.1, 0.3, -0.2);
+            for k in [0.5, 0.1, 1e-3].iter() {
+                let smooth_union = a.clone().smooth_union(b.clone(), *k).distance(p);
+                let smooth_subtraction = a.clone().smooth_subtract(b.clone(), *k).distance(p);
+                // Blending only ever adds material to a union and removes it from a subtraction, at most k / 4
+                let (union, subtraction) = (hard_union.distance(p), hard_subtraction.distance(p));
+                assert!(smooth_union <= union + 1e-12 && smooth_union >= union - k * 0.25);
+                assert!(smooth_subtraction >= subtraction - 1e-12 && smooth_subtraction <= subtraction + k * 0.25,
+                        "at {:?} with k = {}: {} against {}", p, k, smooth_subtraction, subtraction);
+            }
+        }
+    }
+
+    #[test]
+    fn sphere_tracing_finds_the_surface() {
+        let surface = SdfSurface { sdf: Sdf::sphere(1.0), epsilon: 1e-5 };
+        let hits = surface.intersect_all(&Ray::new(Point3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0)));
+        assert_eq!(hits.len(), 2);
+        assert!((hits[0].distance - 4.0).abs() < 1e-4 && (hits[1].distance - 6.0).abs() < 1e-4);
+        assert!(hits[0].front_face && !hits[1].front_face);
+        assert!((hits[0].geometric_normal - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-4);
+
+        // A torus seen from above is hit on the top of its tube
+        let surface = SdfSurface { sdf: Sdf::torus(2.0, 0.5), epsilon: 1e-5 };
+        let hit = surface.intersect(&Ray::new(Point3::new(2.0, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0))).unwrap();
+        assert!((hit.distance - 4.5).abs() < 1e-4 && (hit.geometric_normal - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-4);
+        assert!(surface.intersect(&Ray::new(Point3::new(0.0, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0))).is_none());
+    }
+}
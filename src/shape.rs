@@ -0,0 +1,53 @@
+use crate::{Ray, HitRecord, Aabb};
+use cgmath::{InnerSpace, Vector2, Vector3, Point3};
+
+/// Immutable geometry in its own object space, shared between the node that authors it and the compiled scene.
+///
+/// Hits leave `material` empty unless the shape is made of parts with their own materials, the primitive that
+/// places the shape fills it in.
+pub trait Shape: Send + Sync {
+    /// Every surface crossing of an object space ray within its extent ordered by distance, closed solids
+    /// report where the ray enters and leaves them. Open surfaces only need the nearest hit.
+    fn intersect_all(&self, ray: &Ray) -> Vec<HitRecord>;
+
+    /// Nearest crossing of an object space ray within its extent.
+    fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        self.intersect_all(ray).into_iter().next()
+    }
+
+    /// Object space bounds, `None` if the shape is unbounded.
+    fn bounds(&self) -> Option<Aabb>;
+
+    /// Point on the surface for a uniform sample `u` in the unit square, used to light the scene with emissive
+    /// shapes. `None` if the shape cannot be sampled.
+    fn sample(&self, _u: Vector2<f64>) -> Option<SurfaceSample> {
+        None
+    }
+}
+
+/// Object space point picked on the surface of a shape.
+pub struct SurfaceSample {
+    pub position: Point3<f64>,
+    /// Tangents whose cross product is the outward normal scaled by the surface area the sample stands for.
+    ///
+    /// They are transformed along with the position, so the area stays right under any affine placement.
+    pub dpdu: Vector3<f64>,
+    pub dpdv: Vector3<f64>,
+    /// Texture coordinate a hit at the same point reports.
+    pub uv: Vector2<f64>,
+}
+
+impl SurfaceSample {
+    /// Sample of a surface with the given outward unit `normal` and `area`.
+    pub fn with_area(position: Point3<f64>, normal: Vector3<f64>, area: f64, uv: Vector2<f64>) -> SurfaceSample {
+        // Any tangent perpendicular to the normal completes a right-handed frame with normal x tangent
+        let helper = if normal.x.abs() < 0.9 { Vector3::new(1.0, 0.0, 0.0) } else { Vector3::new(0.0, 1.0, 0.0) };
+        let tangent = helper.cross(normal).normalize();
+        SurfaceSample {
+            position: position,
+            dpdu: tangent * area,
+            dpdv: normal.cross(tangent),
+            uv: uv,
+        }
+    }
+}
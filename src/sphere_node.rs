@@ -1,99 +1,144 @@
-use crate::{Node, Color, Ray};
-use std::rc::{Weak, Rc};
-use cgmath::{InnerSpace, Vector3, Matrix4};
-use std::cell::RefCell;
+use crate::{Node, Color, Ray, Material, HitRecord, Aabb, Shape, SurfaceSample};
+use crate::compiled_scene::SceneCompiler;
+use crate::node::NodeCore;
+use crate::hit_record::{abs, gamma};
+use crate::polynomial::solve_quadratic;
+use std::sync::Arc;
+use cgmath::{InnerSpace, Vector2, Vector3, Point3, Matrix4, EuclideanSpace};
+use std::f64::consts::PI;
 
-pub struct SphereNode {
-    childs: Vec<Rc<RefCell<Node>>>,
-    parent: Weak<Rc<RefCell<Node>>>,
-    size: usize,
-    pub name: String,
-    pub frame_transform: Matrix4<f64>,
-    pub world_transform: Matrix4<f64>,
-    //pub center: Point3<f64>,
+/// Sphere around the local origin.
+#[derive(Clone)]
+pub struct Sphere {
     pub radius: f64,
-    pub color: Color,
+}
+
+/// Sphere placed by its transform, so scaled or rotated parents turn it into an ellipsoid.
+pub struct SphereNode {
+    pub core: NodeCore,
+    pub shape: Arc<Sphere>,
+    pub material: Arc<Material>,
 }
 
 impl SphereNode {
     pub fn new(name: String, transform: Matrix4<f64>, radius: f64, color: Color) -> SphereNode {
         SphereNode {
-            childs: vec![],
-            parent: Weak::new(),
-            size: 0,
-            name: name,
-            frame_transform: transform,
-            world_transform: transform,
-            radius: radius,
-            color: color,
+            core: NodeCore::new(name, transform),
+            shape: Arc::new(Sphere { radius: radius }),
+            material: Arc::new(Material::from_color(color)),
         }
     }
 }
 
-impl Node for SphereNode {
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        let strong = &self.parent.upgrade();
-        let strong = match strong {
-            Some(x) => x,
-            None => return None,
+impl Sphere {
+    /// Spherical mapping of a local surface point, u runs around the local y axis and v from the south to the north pole.
+    /// Returns the UV and the local derivatives of the position along u and v.
+    fn surface_frame(&self, local: Vector3<f64>) -> (Vector2<f64>, Vector3<f64>, Vector3<f64>) {
+        let direction = local.normalize();
+        let u = 0.5 - direction.z.atan2(direction.x) / (2.0 * PI);
+        let v = 0.5 + direction.y.clamp(-1.0, 1.0).asin() / PI;
+
+        let rho = (local.x * local.x + local.z * local.z).sqrt();
+        // u grows clockwise around y and v towards +y
+        let dpdu = Vector3::new(local.z, 0.0, -local.x) * (2.0 * PI);
+        let dpdv = if rho > 1e-9 {
+            Vector3::new(-local.y * local.x / rho, rho, -local.y * local.z / rho) * PI
+        } else {
+            // At the poles the longitude is undefined, any tangent direction will do
+            Vector3::new(local.magnitude() * PI, 0.0, 0.0)
         };
-        return Some(Rc::clone(&(*strong))); //Some(Rc::clone(&(*(&self.parent))));
+        let dpdu = if rho > 1e-9 { dpdu } else { dpdv.cross(local).normalize() * (2.0 * PI) };
+        (Vector2::new(u, v), dpdu, dpdv)
     }
+}
 
-    fn get_child(&self, index: usize) -> Option<Rc<RefCell<Node>>> {
-        if index >= self.size {
-            return None;
-        }
-        return Some(Rc::clone(&(self.childs[index])));
-    }
+impl Shape for Sphere {
+    fn intersect_all(&self, local_ray: &Ray) -> Vec<HitRecord> {
+        let origin = local_ray.origin.to_vec();
+        let direction = local_ray.direction;
 
-    fn add_child(&mut self, node: Rc<RefCell<Node>>) {
-        self.size += 1;
-        value!(node).set_world_transform(&self.world_transform);
-        self.childs.push(Rc::clone(&node));
-    }
+        let a = direction.dot(direction);
+        let b = 2.0 * origin.dot(direction);
+        let c = origin.dot(origin) - self.radius * self.radius;
+        let (t0, t1) = match solve_quadratic(a, b, c) {
+            Some(roots) if a != 0.0 => roots,
+            _ => return vec![],
+        };
 
-    fn get_size(&self) -> usize {
-        return self.size;
+        [t0, t1].iter().filter(|t| local_ray.contains(**t)).map(|distance| {
+            // Reproject onto the surface, the solved distance suffers from cancellation near grazing angles
+            let local = origin + direction * *distance;
+            let local = local * (self.radius / local.magnitude());
+            let (uv, dpdu, dpdv) = self.surface_frame(local);
+            let mut hit = HitRecord::new(local_ray, *distance, local, uv, dpdu, dpdv, None);
+            hit.position = Point3::from_vec(local);
+            hit.error = abs(local) * gamma(5);
+            hit
+        }).collect()
     }
 
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vector3<f64>)> {
-        let origin = Vector3::new(ray.origin.x, ray.origin.y, ray.origin.z);
-        let l = self.world_transform.w.truncate() - origin;
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::centered(Vector3::new(self.radius, self.radius, self.radius)))
+    }
 
-        let adj = l.dot(ray.direction);
-        let d2 = l.dot(l) - (adj * adj);
-        let radius2 = self.radius * self.radius;
-        if d2 > radius2 {
-            return None;
-        }
+    /// Uniform over the whole surface.
+    fn sample(&self, u: Vector2<f64>) -> Option<SurfaceSample> {
+        let z = 1.0 - 2.0 * u.y;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.x;
+        let normal = Vector3::new(r * phi.cos(), r * phi.sin(), z);
+        let area = 4.0 * PI * self.radius * self.radius;
+        let (uv, _, _) = self.surface_frame(normal * self.radius);
+        Some(SurfaceSample::with_area(Point3::from_vec(normal * self.radius), normal, area, uv))
+    }
+}
 
-        let thc = (radius2 - d2).sqrt();
-        let t0 = adj - thc;
-        let t1 = adj + thc;
+impl Node for SphereNode {
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
 
-        if t0 < 0.0 && t1 < 0.0 {
-              return None;
-        }
-        //println!("test");
-        let distance = if t0 < t1 { if t0 > 0.0 { t0 } else { t1 } } else { t1 };
-
-        let hit_point = ray.origin + (ray.direction * distance);
-        let normal = hit_point - self.world_transform.w.truncate();
-        let normal = -Vector3::new(normal.x, normal.y, normal.z).normalize();
-        //println!("{}: {}", self.name, distance);
-        Some((distance, normal))
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
     }
 
-    fn get_color(&self) -> Color {
-        return self.color.copy();
+    fn get_shape(&self, _compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        return Some(self.shape.clone());
     }
 
-    fn get_world_transform(&self) -> Matrix4<f64> {
-        return self.world_transform;
+    fn get_material(&self) -> Option<Arc<Material>> {
+        return Some(Arc::clone(&self.material));
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Scene, Node3D};
+    use crate::compiled_scene::CompiledScene;
+    use crate::node::add_child;
+    use std::rc::Rc;
+    use std::cell::RefCell;
 
-    fn set_world_transform(&mut self, transform: &Matrix4<f64>) -> () {
-        self.world_transform = transform * self.frame_transform;
+    #[test]
+    fn scaled_parent_turns_the_sphere_into_an_ellipsoid() {
+        let root: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new("root".to_string(), Matrix4::from_scale(1.0))));
+        let parent: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(Node3D::new("Stretch".to_string(),
+            Matrix4::from_translation(Vector3::new(0.0, 0.0, -5.0)) * Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0))));
+        add_child(&parent, Rc::new(RefCell::new(SphereNode::new("Sphere".to_string(),
+            Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0)), 1.0, Color::new(1.0, 1.0, 1.0)))));
+        add_child(&root, parent);
+        let compiled = CompiledScene::new(&Scene::new("scene".to_string(), root)).unwrap();
+
+        // Center at (2, 0, -5) with semi-axes 2, 1 and 1
+        let (hit, _) = compiled.intersect(&Ray::new(Point3::new(10.0, 0.0, -5.0), Vector3::new(-1.0, 0.0, 0.0))).unwrap();
+        assert!((hit.distance - 6.0).abs() < 1e-9 && (hit.geometric_normal - Vector3::new(1.0, 0.0, 0.0)).magnitude() < 1e-9);
+        let (hit, _) = compiled.intersect(&Ray::new(Point3::new(10.0, 0.5, -5.0), Vector3::new(-1.0, 0.0, 0.0))).unwrap();
+        let x = 2.0 * 0.75f64.sqrt();
+        assert!((hit.distance - (8.0 - x)).abs() < 1e-9, "distance {}", hit.distance);
+        // The gradient of the implicit surface, not the direction from the center
+        let normal = Vector3::new(x / 4.0, 0.5, 0.0).normalize();
+        assert!((hit.geometric_normal - normal).magnitude() < 1e-9, "normal {:?}", hit.geometric_normal);
+        assert!(compiled.intersect(&Ray::new(Point3::new(10.0, 1.1, -5.0), Vector3::new(-1.0, 0.0, 0.0))).is_none());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,150 @@
+use crate::Color;
+use cgmath::{Point3, Vector2};
+use image::{DynamicImage, GenericImage, RgbaImage};
+use std::io;
+
+/// Surface location a texture is evaluated at.
+pub struct TextureCoord {
+    pub uv: Vector2<f64>,
+    /// Hit point in the local space of the intersected node.
+    pub object_point: Point3<f64>,
+    pub world_point: Point3<f64>,
+}
+
+pub trait Texture: Send + Sync {
+    fn sample(&self, coord: &TextureCoord) -> Color;
+
+    /// Scalar lookup for material parameters that are not colors, the average of the three channels.
+    fn sample_float(&self, coord: &TextureCoord) -> f32 {
+        let c = self.sample(coord);
+        (c.red + c.green + c.blue) / 3.0
+    }
+}
+
+pub struct ConstantTexture {
+    pub color: Color,
+}
+
+impl ConstantTexture {
+    pub fn new(color: Color) -> ConstantTexture {
+        ConstantTexture {
+            color: color,
+        }
+    }
+}
+
+impl Texture for ConstantTexture {
+    fn sample(&self, _coord: &TextureCoord) -> Color {
+        return self.color.copy();
+    }
+}
+
+/// How texel lookups outside of `[0, 1)` are folded back onto the image.
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+pub enum FilterMode {
+    Nearest,
+    Bilinear,
+}
+
+pub struct ImageTexture {
+    image: RgbaImage,
+    pub wrap: WrapMode,
+    pub filter: FilterMode,
+    /// Number of image repetitions per unit of UV space.
+    pub scale: Vector2<f64>,
+}
+
+impl ImageTexture {
+    /// Fails with `InvalidData` for an image without pixels, which has no texel to wrap lookups onto.
+    pub fn new(image: DynamicImage) -> io::Result<ImageTexture> {
+        let (width, height) = image.dimensions();
+        if width == 0 || height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("texture image is {}x{}", width, height)));
+        }
+        Ok(ImageTexture {
+            image: image.to_rgba(),
+            wrap: WrapMode::Repeat,
+            filter: FilterMode::Bilinear,
+            scale: Vector2::new(1.0, 1.0),
+        })
+    }
+
+    pub fn open(path: &str) -> io::Result<ImageTexture> {
+        let image = image::open(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        ImageTexture::new(image)
+    }
+
+    fn wrap(&self, i: i64, size: u32) -> u32 {
+        let size = size as i64;
+        let i = match self.wrap {
+            WrapMode::Repeat => i.rem_euclid(size),
+            WrapMode::Clamp => i.clamp(0, size - 1),
+            WrapMode::Mirror => {
+                let period = i.rem_euclid(2 * size);
+                if period < size { period } else { 2 * size - 1 - period }
+            }
+        };
+        i as u32
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Color {
+        let (width, height) = self.image.dimensions();
+        let p = self.image.get_pixel(self.wrap(x, width), self.wrap(y, height));
+        Color::new_rgb(p.data[0], p.data[1], p.data[2])
+    }
+}
+
+impl Texture for ImageTexture {
+    fn sample(&self, coord: &TextureCoord) -> Color {
+        let (width, height) = self.image.dimensions();
+        // Texel centers lie at half integers, v runs from the bottom of the image to the top
+        let x = coord.uv.x * self.scale.x * width as f64 - 0.5;
+        let y = (1.0 - coord.uv.y * self.scale.y) * height as f64 - 0.5;
+
+        match self.filter {
+            FilterMode::Nearest => self.texel(x.round() as i64, y.round() as i64),
+            FilterMode::Bilinear => {
+                let x0 = x.floor();
+                let y0 = y.floor();
+                let fx = (x - x0) as f32;
+                let fy = (y - y0) as f32;
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                let top = self.texel(x0, y0) * (1.0 - fx) + self.texel(x0 + 1, y0) * fx;
+                let bottom = self.texel(x0, y0 + 1) * (1.0 - fx) + self.texel(x0 + 1, y0 + 1) * fx;
+                top * (1.0 - fy) + bottom * fy
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn empty_images_are_rejected() {
+        for (width, height) in [(0, 4), (4, 0), (0, 0)].iter() {
+            let result = ImageTexture::new(DynamicImage::new_rgba8(*width, *height));
+            assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        }
+    }
+
+    #[test]
+    fn lookups_wrap_onto_the_image() {
+        let mut image = DynamicImage::new_rgba8(2, 1);
+        image.put_pixel(1, 0, Rgba { data: [255, 255, 255, 255] });
+        let mut texture = ImageTexture::new(image).unwrap();
+        let wrapped = |texture: &ImageTexture| (-2..4).map(|x| texture.texel(x, 0).red).collect::<Vec<f32>>();
+        assert_eq!(wrapped(&texture), vec![0.0, 1.0, 0.0, 1.0, 0.0, 1.0]);
+        texture.wrap = WrapMode::Clamp;
+        assert_eq!(wrapped(&texture), vec![0.0, 0.0, 0.0, 1.0, 1.0, 1.0]);
+        texture.wrap = WrapMode::Mirror;
+        assert_eq!(wrapped(&texture), vec![1.0, 0.0, 0.0, 1.0, 1.0, 0.0]);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{Node, Color, Ray, Material, HitRecord, Aabb, Shape};
+use crate::compiled_scene::SceneCompiler;
+use crate::node::NodeCore;
+use crate::hit_record::{abs, gamma};
+use crate::polynomial::real_roots;
+use std::sync::Arc;
+use cgmath::{InnerSpace, Vector2, Vector3, Matrix4, EuclideanSpace};
+use std::f64::consts::PI;
+
+/// Torus lying in the local xz plane around the local y axis.
+#[derive(Clone)]
+pub struct Torus {
+    /// Distance from the center of the torus to the center of the tube.
+    pub major_radius: f64,
+    /// Radius of the tube.
+    pub minor_radius: f64,
+}
+
+/// Scene node placing a `Torus`.
+pub struct TorusNode {
+    pub core: NodeCore,
+    pub shape: Arc<Torus>,
+    pub material: Arc<Material>,
+}
+
+impl TorusNode {
+    pub fn new(name: String, transform: Matrix4<f64>, major_radius: f64, minor_radius: f64, color: Color) -> TorusNode {
+        TorusNode {
+            core: NodeCore::new(name, transform),
+            shape: Arc::new(Torus {
+                major_radius: major_radius,
+                minor_radius: minor_radius,
+            }),
+            material: Arc::new(Material::from_color(color)),
+        }
+    }
+}
+
+impl Torus {
+    fn local_bounds(&self) -> Aabb {
+        let outer = self.major_radius + self.minor_radius;
+        Aabb::centered(Vector3::new(outer, self.minor_radius, outer))
+    }
+
+    fn surface_hit(&self, ray: &Ray, distance: f64) -> HitRecord {
+        let p = ray.origin + ray.direction * distance;
+        let (x, y, z) = (p.x, p.y, p.z);
+        let rho = (x * x + z * z).sqrt();
+        let s = x * x + y * y + z * z - self.major_radius * self.major_radius - self.minor_radius * self.minor_radius;
+        let normal = Vector3::new(x * s, y * (s + 2.0 * self.major_radius * self.major_radius), z * s);
+
+        // u runs around the y axis like on SphereNode, v around the tube starting at its outer equator
+        let u = (-z).atan2(x).rem_euclid(2.0 * PI) / (2.0 * PI);
+        let v = y.atan2(rho - self.major_radius).rem_euclid(2.0 * PI) / (2.0 * PI);
+        let dpdu = Vector3::new(z, 0.0, -x) * (2.0 * PI);
+        let dpdv = if rho > 0.0 {
+            Vector3::new(-y * x / rho, rho - self.major_radius, -y * z / rho) * (2.0 * PI)
+        } else {
+            Vector3::new(0.0, 2.0 * PI * self.minor_radius, 0.0)
+        };
+
+        let mut hit = HitRecord::new(ray, distance, normal, Vector2::new(u, v), dpdu, dpdv, None);
+        // The root is only as accurate as the evaluation of the quartic, widen the bound to cover it
+        hit.error += (abs(p.to_vec()) + Vector3::new(1.0, 1.0, 1.0) * (self.major_radius + self.minor_radius)) * gamma(64);
+        hit
+    }
+}
+
+impl Shape for Torus {
+    fn intersect_all(&self, ray: &Ray) -> Vec<HitRecord> {
+        let (t_enter, t_exit) = match self.local_bounds().intersect(ray) {
+            Some(range) => range,
+            None => return vec![],
+        };
+
+        // Solve for the distance s along the normalized direction starting where the ray enters the bounds,
+        // keeps the coefficients of the quartic small and well conditioned
+        let length = ray.direction.magnitude();
+        let d = ray.direction / length;
+        let o = (ray.origin + ray.direction * t_enter).to_vec();
+        let (r2, m2) = (self.major_radius * self.major_radius, self.minor_radius * self.minor_radius);
+        let od = o.dot(d);
+        let k = o.dot(o) + r2 - m2;
+        let coefficients = [
+            1.0,
+            4.0 * od,
+            2.0 * k + 4.0 * od * od - 4.0 * r2 * (d.x * d.x + d.z * d.z),
+            4.0 * od * k - 8.0 * r2 * (o.x * d.x + o.z * d.z),
+            k * k - 4.0 * r2 * (o.x * o.x + o.z * o.z),
+        ];
+
+        real_roots(&coefficients, 0.0, (t_exit - t_enter) * length).into_iter()
+            .map(|s| t_enter + s / length)
+            .filter(|t| ray.contains(*t))
+            .map(|t| self.surface_hit(ray, t))
+            .collect()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.local_bounds())
+    }
+}
+
+impl Node for TorusNode {
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
+
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
+    }
+
+    fn get_shape(&self, _compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        return Some(self.shape.clone());
+    }
+
+    fn get_material(&self) -> Option<Arc<Material>> {
+        return Some(Arc::clone(&self.material));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Point3, InnerSpace};
+
+    #[test]
+    fn ray_along_axis_crosses_the_tube_twice() {
+        let torus = Torus { major_radius: 2.0, minor_radius: 0.5 };
+        let hits = torus.intersect_all(&Ray::new(Point3::new(5.0, 0.0, 0.0), Vector3::new(-1.0, 0.0, 0.0)));
+        let distances: Vec<f64> = hits.iter().map(|hit| hit.distance).collect();
+        assert_eq!(distances.len(), 4);
+        for (distance, expected) in distances.iter().zip([2.5, 3.5, 6.5, 7.5].iter()) {
+            assert!((distance - expected).abs() < 1e-7, "{:?}", distances);
+        }
+        assert!((hits[0].geometric_normal - Vector3::new(1.0, 0.0, 0.0)).magnitude() < 1e-6);
+    }
+
+    #[test]
+    fn ray_from_above_hits_top_of_tube() {
+        let torus = Torus { major_radius: 2.0, minor_radius: 0.5 };
+        let hit = torus.intersect(&Ray::new(Point3::new(0.0, 5.0, 2.0), Vector3::new(0.0, -1.0, 0.0))).unwrap();
+        assert!((hit.distance - 4.5).abs() < 1e-7 && hit.front_face);
+        assert!((hit.geometric_normal - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-6);
+        // Straight through the hole
+        assert!(torus.intersect(&Ray::new(Point3::new(0.0, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0))).is_none());
+    }
+}
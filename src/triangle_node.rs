@@ -1,7 +1,385 @@
+use crate::{Node, Color, Ray, Material, Texture, TextureCoord, HitRecord, Aabb, Shape, SurfaceSample, Bvh};
+use crate::compiled_scene::SceneCompiler;
+use crate::node::NodeCore;
+use crate::hit_record::{abs, gamma};
+use std::sync::Arc;
+use cgmath::{InnerSpace, Vector2, Vector3, Point3, Matrix4, Transform, EuclideanSpace};
+use std::collections::HashMap;
+
+/// Triangle mesh in local space, traced through a `MeshShape`.
+#[derive(Clone)]
+pub struct Mesh {
+    pub vertices: Vec<Point3<f64>>,
+    /// One texture coordinate per vertex, may be empty for untextured meshes.
+    pub uvs: Vec<Vector2<f64>>,
+    pub indices: Vec<[usize; 3]>,
+}
+
+/// Mesh with a BVH over its triangles, built when the scene is compiled so edits to the mesh never leave it stale.
+pub struct MeshShape {
+    mesh: Arc<Mesh>,
+    bvh: Bvh,
+}
+
+/// Triangle mesh node, vertices are given in the local space of the node.
 pub struct TriangleNode {
-    childs: Vec<Rc<RefCell<Node>>>,
-    parent: Weak<Rc<RefCell<Node>>>,
-    size: usize,
-    pub name: String,
-    pub frame_transform: Matrix4<f64>,
-}
\ No newline at end of file
+    pub core: NodeCore,
+    pub shape: Arc<Mesh>,
+    pub material: Arc<Material>,
+}
+
+impl TriangleNode {
+    pub fn new(name: String, transform: Matrix4<f64>, vertices: Vec<Point3<f64>>, uvs: Vec<Vector2<f64>>,
+               indices: Vec<[usize; 3]>, color: Color) -> TriangleNode {
+        TriangleNode {
+            core: NodeCore::new(name, transform),
+            shape: Arc::new(Mesh {
+                vertices: vertices,
+                uvs: uvs,
+                indices: indices,
+            }),
+            material: Arc::new(Material::from_color(color)),
+        }
+    }
+
+    /// See `Mesh::subdivide`.
+    pub fn subdivide(&mut self) {
+        Arc::make_mut(&mut self.shape).subdivide();
+    }
+
+    /// Moves every vertex along its area weighted normal by `scale` times the height sampled at its UV.
+    ///
+    /// Unlike bump mapping this changes the silhouette, so dense meshes (see `subdivide`) give the best results.
+    pub fn displace(&mut self, height: &dyn Texture, scale: f64) {
+        let world_transform = self.core.world_transform;
+        let mesh = Arc::make_mut(&mut self.shape);
+        let mut normals = vec![Vector3::new(0.0, 0.0, 0.0); mesh.vertices.len()];
+        for triangle in &mesh.indices {
+            let p0 = mesh.vertices[triangle[0]];
+            let normal = (mesh.vertices[triangle[1]] - p0).cross(mesh.vertices[triangle[2]] - p0);
+            for index in triangle.iter() {
+                normals[*index] += normal;
+            }
+        }
+
+        let has_uvs = mesh.uvs.len() == mesh.vertices.len();
+        for (i, normal) in normals.iter().enumerate() {
+            if normal.magnitude2() == 0.0 {
+                continue;
+            }
+            let coord = TextureCoord {
+                uv: if has_uvs { mesh.uvs[i] } else { Vector2::new(0.0, 0.0) },
+                object_point: mesh.vertices[i],
+                world_point: world_transform.transform_point(mesh.vertices[i]),
+            };
+            let offset = height.sample_float(&coord) as f64 * scale;
+            mesh.vertices[i] += normal.normalize() * offset;
+        }
+    }
+}
+
+impl Mesh {
+    /// Splits every triangle into four at its edge midpoints, shared edges get a single new vertex.
+    pub fn subdivide(&mut self) {
+        let has_uvs = self.uvs.len() == self.vertices.len();
+        let mut midpoints: HashMap<(usize, usize), usize> = HashMap::new();
+        let mut indices = Vec::with_capacity(self.indices.len() * 4);
+        for triangle in self.indices.clone() {
+            let mut mid = [0; 3];
+            for i in 0..3 {
+                let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+                let key = if a < b { (a, b) } else { (b, a) };
+                mid[i] = match midpoints.get(&key) {
+                    Some(index) => *index,
+                    None => {
+                        let point = self.vertices[a] + (self.vertices[b] - self.vertices[a]) * 0.5;
+                        self.vertices.push(point);
+                        if has_uvs {
+                            let uv = (self.uvs[a] + self.uvs[b]) * 0.5;
+                            self.uvs.push(uv);
+                        }
+                        midpoints.insert(key, self.vertices.len() - 1);
+                        self.vertices.len() - 1
+                    }
+                };
+            }
+            indices.push([triangle[0], mid[0], mid[2]]);
+            indices.push([mid[0], triangle[1], mid[1]]);
+            indices.push([mid[2], mid[1], triangle[2]]);
+            indices.push([mid[0], mid[1], mid[2]]);
+        }
+        self.indices = indices;
+    }
+
+    /// Interpolated UV at the barycentric coordinates and the derivatives of the position along u and v.
+    fn surface_frame(&self, triangle: &[usize; 3], e1: Vector3<f64>, e2: Vector3<f64>, b1: f64, b2: f64)
+                     -> (Vector2<f64>, Vector3<f64>, Vector3<f64>) {
+        if self.uvs.len() != self.vertices.len() {
+            // Untextured meshes expose the barycentric coordinates instead
+            return (Vector2::new(b1, b2), e1, e2);
+        }
+        let uv = self.uvs[triangle[0]] * (1.0 - b1 - b2) + self.uvs[triangle[1]] * b1 + self.uvs[triangle[2]] * b2;
+
+        // Solve [e1 e2] = [dpdu dpdv] * [duv1 duv2] for the derivatives
+        let duv1 = self.uvs[triangle[1]] - self.uvs[triangle[0]];
+        let duv2 = self.uvs[triangle[2]] - self.uvs[triangle[0]];
+        let det = duv1.x * duv2.y - duv1.y * duv2.x;
+        if det.abs() < 1e-12 {
+            return (uv, e1, e2);
+        }
+        let inv_det = 1.0 / det;
+        (uv, (e1 * duv2.y - e2 * duv1.y) * inv_det, (e2 * duv1.x - e1 * duv2.x) * inv_det)
+    }
+
+    /// Möller-Trumbore test against one triangle, returns the distance and barycentric coordinates.
+    fn intersect_triangle(&self, ray: &Ray, triangle: &[usize; 3]) -> Option<(f64, f64, f64)> {
+        let p0 = self.vertices[triangle[0]];
+        let e1 = self.vertices[triangle[1]] - p0;
+        let e2 = self.vertices[triangle[2]] - p0;
+
+        let p = ray.direction.cross(e2);
+        let det = e1.dot(p);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = ray.origin - p0;
+        let b1 = s.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+
+        let q = s.cross(e1);
+        let b2 = ray.direction.dot(q) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+
+        let distance = e2.dot(q) * inv_det;
+        if !ray.contains(distance) {
+            return None;
+        }
+        Some((distance, b1, b2))
+    }
+
+    fn triangle_bounds(&self, triangle: &[usize; 3]) -> Aabb {
+        Aabb::from_points(&[self.vertices[triangle[0]], self.vertices[triangle[1]], self.vertices[triangle[2]]])
+    }
+
+    /// Object space hit on triangle `index` at the given distance and barycentric coordinates.
+    fn triangle_hit(&self, ray: &Ray, distance: f64, index: usize, b1: f64, b2: f64) -> HitRecord {
+        let triangle = &self.indices[index];
+        let p0 = self.vertices[triangle[0]];
+        let e1 = self.vertices[triangle[1]] - p0;
+        let e2 = self.vertices[triangle[2]] - p0;
+        let (uv, dpdu, dpdv) = self.surface_frame(triangle, e1, e2, b1, b2);
+        // Triangles are two sided, the winding only decides which side counts as the front
+        let mut hit = HitRecord::new(ray, distance, e1.cross(e2), uv, dpdu, dpdv, None);
+        hit.primitive_index = index;
+        // Evaluate the position from the barycentrics, its error only depends on the vertex magnitudes
+        let (b0, p1, p2) = (1.0 - b1 - b2, self.vertices[triangle[1]], self.vertices[triangle[2]]);
+        hit.position = Point3::from_vec(p0.to_vec() * b0 + p1.to_vec() * b1 + p2.to_vec() * b2);
+        hit.error = (abs(p0.to_vec() * b0) + abs(p1.to_vec() * b1) + abs(p2.to_vec() * b2)) * gamma(7);
+        hit
+    }
+}
+
+impl MeshShape {
+    pub fn new(mesh: Arc<Mesh>) -> MeshShape {
+        let bounds: Vec<Option<Aabb>> = mesh.indices.iter().map(|triangle| Some(mesh.triangle_bounds(triangle))).collect();
+        MeshShape {
+            bvh: Bvh::new(&bounds),
+            mesh: mesh,
+        }
+    }
+}
+
+impl Shape for MeshShape {
+    fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        let mesh = &self.mesh;
+        let mut nearest: Option<(f64, usize, f64, f64)> = None;
+        self.bvh.traverse(ray, |index| {
+            if let Some((distance, b1, b2)) = mesh.intersect_triangle(ray, &mesh.indices[index]) {
+                if nearest.is_none_or(|(d, _, _, _)| distance < d) {
+                    nearest = Some((distance, index, b1, b2));
+                }
+            }
+            nearest.map_or(f64::INFINITY, |(d, _, _, _)| d)
+        });
+
+        let (distance, index, b1, b2) = nearest?;
+        Some(mesh.triangle_hit(ray, distance, index, b1, b2))
+    }
+
+    /// Hits on every triangle, a closed mesh reports where the ray enters and leaves it.
+    fn intersect_all(&self, ray: &Ray) -> Vec<HitRecord> {
+        let mesh = &self.mesh;
+        let mut hits = vec![];
+        self.bvh.traverse(ray, |index| {
+            if let Some((distance, b1, b2)) = mesh.intersect_triangle(ray, &mesh.indices[index]) {
+                hits.push(mesh.triangle_hit(ray, distance, index, b1, b2));
+            }
+            f64::INFINITY
+        });
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.bvh.bounds()
+    }
+
+    fn sample(&self, u: Vector2<f64>) -> Option<SurfaceSample> {
+        self.mesh.sample(u)
+    }
+}
+
+impl Mesh {
+    /// Picks a triangle by its area and a uniform point on it, the winding decides the outward side.
+    fn sample(&self, u: Vector2<f64>) -> Option<SurfaceSample> {
+        let edges = |triangle: &[usize; 3]| {
+            let p0 = self.vertices[triangle[0]];
+            (p0, self.vertices[triangle[1]] - p0, self.vertices[triangle[2]] - p0)
+        };
+        let areas: Vec<f64> = self.indices.iter().map(|triangle| {
+            let (_, e1, e2) = edges(triangle);
+            e1.cross(e2).magnitude() * 0.5
+        }).collect();
+        let total: f64 = areas.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+
+        // Reuse the part of u.x below the chosen triangle's share as a fresh uniform number
+        let mut target = u.x * total;
+        let mut index = 0;
+        while index + 1 < areas.len() && (target >= areas[index] || areas[index] == 0.0) {
+            target -= areas[index];
+            index += 1;
+        }
+        let probability = areas[index] / total;
+        let s = (target / areas[index]).clamp(0.0, 1.0).sqrt();
+        let (b1, b2) = (s * (1.0 - u.y), s * u.y);
+
+        let (p0, e1, e2) = edges(&self.indices[index]);
+        let (uv, _, _) = self.surface_frame(&self.indices[index], e1, e2, b1, b2);
+        // Half of e1 x e2 is the triangle, dividing by its probability makes each sample stand for the whole mesh
+        Some(SurfaceSample {
+            position: p0 + e1 * b1 + e2 * b2,
+            dpdu: e1 / probability,
+            dpdv: e2 * 0.5,
+            uv: uv,
+        })
+    }
+}
+
+impl Node for TriangleNode {
+    fn core(&self) -> &NodeCore {
+        return &self.core;
+    }
+
+    fn core_mut(&mut self) -> &mut NodeCore {
+        return &mut self.core;
+    }
+
+    fn get_shape(&self, _compiler: &mut SceneCompiler) -> Option<Arc<dyn Shape>> {
+        return Some(Arc::new(MeshShape::new(self.shape.clone())));
+    }
+
+    fn get_material(&self) -> Option<Arc<Material>> {
+        return Some(Arc::clone(&self.material));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConstantTexture;
+    use crate::procedural_texture::{GradientTexture, TextureSpace, ColorRamp};
+
+    /// Octahedron subdivided and pushed onto the unit sphere, a closed mesh with a few hundred triangles.
+    fn sphere_mesh() -> Mesh {
+        let mut mesh = Mesh {
+            vertices: vec![Point3::new(1.0, 0.0, 0.0), Point3::new(-1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0),
+                           Point3::new(0.0, -1.0, 0.0), Point3::new(0.0, 0.0, 1.0), Point3::new(0.0, 0.0, -1.0)],
+            uvs: vec![],
+            indices: vec![[0, 2, 4], [2, 1, 4], [1, 3, 4], [3, 0, 4], [2, 0, 5], [1, 2, 5], [3, 1, 5], [0, 3, 5]],
+        };
+        for _ in 0..3 {
+            mesh.subdivide();
+        }
+        for vertex in mesh.vertices.iter_mut() {
+            *vertex = Point3::from_vec(vertex.to_vec().normalize());
+        }
+        mesh
+    }
+
+    #[test]
+    fn subdivide_shares_edge_midpoints() {
+        let mesh = sphere_mesh();
+        assert_eq!(mesh.indices.len(), 8 * 64);
+        // A closed triangle mesh of genus zero has V - E + F = 2 with E = 3F / 2
+        assert_eq!(mesh.vertices.len(), 2 + mesh.indices.len() / 2);
+    }
+
+    #[test]
+    fn bvh_matches_brute_force() {
+        let mesh = sphere_mesh();
+        let shape = MeshShape::new(Arc::new(mesh.clone()));
+        for i in 0..15 {
+            for j in 0..15 {
+                let origin = Point3::new(-1.2 + i as f64 * 0.17, -1.2 + j as f64 * 0.17, 3.0);
+                let ray = Ray::new(origin, Vector3::new(0.05, -0.03, -1.0).normalize());
+
+                let brute: Vec<(f64, usize)> = mesh.indices.iter().enumerate()
+                    .filter_map(|(index, triangle)| mesh.intersect_triangle(&ray, triangle).map(|hit| (hit.0, index)))
+                    .collect();
+                let nearest = brute.iter().min_by(|a, b| a.0.total_cmp(&b.0));
+
+                let hit = shape.intersect(&ray);
+                assert_eq!(hit.as_ref().map(|hit| hit.distance), nearest.map(|x| x.0));
+                assert_eq!(hit.as_ref().map(|hit| hit.primitive_index), nearest.map(|x| x.1));
+                assert_eq!(shape.intersect_all(&ray).len(), brute.len());
+            }
+        }
+    }
+
+    #[test]
+    fn ray_through_center_enters_and_leaves() {
+        let shape = MeshShape::new(Arc::new(sphere_mesh()));
+        let ray = Ray::new(Point3::new(0.01, 0.02, 3.0), Vector3::new(0.0, 0.0, -1.0));
+        let hits = shape.intersect_all(&ray);
+        assert_eq!(hits.len(), 2);
+        assert!(hits[0].front_face && !hits[1].front_face);
+        assert!((hits[0].distance - 2.0).abs() < 0.05 && (hits[1].distance - 4.0).abs() < 0.05);
+    }
+
+    /// Gray level rising from 0 to 0.5 as u goes from 0 to 1.
+    fn ramp_u() -> GradientTexture {
+        GradientTexture::new(TextureSpace::Uv, 0.5, Vector3::new(1.0, 0.0, 0.0),
+                             ColorRamp::two(Color::new(0.0, 0.0, 0.0), Color::new(1.0, 1.0, 1.0)))
+    }
+
+    #[test]
+    fn displacement_moves_vertices_along_their_normals() {
+        let corners = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(1.0, 1.0, 0.0),
+                           Point3::new(0.0, 1.0, 0.0)];
+        let uvs = corners.iter().map(|p| Vector2::new(p.x, p.y)).collect();
+        let mut quad = TriangleNode::new("Quad".to_string(), Matrix4::from_scale(1.0), corners, uvs,
+                                         vec![[0, 1, 2], [0, 2, 3]], Color::new(1.0, 1.0, 1.0));
+        quad.displace(&ramp_u(), 1.0);
+        let heights: Vec<f64> = quad.shape.vertices.iter().map(|p| p.z).collect();
+        assert_eq!(heights, vec![0.0, 0.5, 0.5, 0.0]);
+
+        // A constant height pushes every vertex of the sphere outwards by the same amount
+        let mut sphere = TriangleNode::new("Sphere".to_string(), Matrix4::from_scale(1.0), vec![], vec![], vec![],
+                                           Color::new(1.0, 1.0, 1.0));
+        let mesh = sphere_mesh();
+        sphere.shape = Arc::new(mesh.clone());
+        sphere.displace(&ConstantTexture::new(Color::new(1.0, 1.0, 1.0)), 0.25);
+        for (before, after) in mesh.vertices.iter().zip(sphere.shape.vertices.iter()) {
+            assert!(((after - before).magnitude() - 0.25).abs() < 1e-9 && after.to_vec().magnitude() > 1.2);
+        }
+    }
+}